@@ -0,0 +1,601 @@
+use std::{
+	fmt::Write as _,
+	fs::{self, File, OpenOptions},
+	io::{self, BufWriter, Write},
+	net::IpAddr,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hyper::{
+	body::HttpBody,
+	header::{CONTENT_LENGTH, REFERER, USER_AGENT},
+	Body, Method, Request, Response, StatusCode, Version,
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+	config::{AccessLog, AccessLogField, AccessLogFormat},
+	metrics::{Completed, InFlight, RouteLabels, METRICS},
+	state::AppState,
+	trace::Span,
+	utils::{
+		control_headers::{self, CacheStatus},
+		host,
+	},
+};
+
+// Lines waiting to be written, more are dropped rather than slowing requests down
+const QUEUE_SIZE: usize = 8192;
+
+/// Upstream that answered a request, attached to the response by the proxy.
+#[derive(Debug, Clone)]
+pub struct UpstreamTiming {
+	pub url: String,
+	// until the response headers were received
+	pub latency: Duration,
+}
+
+enum Message {
+	Line(String, Arc<AccessLog>),
+	Reopen,
+}
+
+/// Queues access log lines for the writer thread.
+#[derive(Clone)]
+pub struct AccessLogger {
+	sender: mpsc::Sender<Message>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl AccessLogger {
+	/// Starts the thread writing the lines, so slow disks never block a request.
+	pub fn start() -> Self {
+		let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+		let dropped = Arc::new(AtomicU64::new(0));
+		let writer = Writer {
+			dropped: Arc::clone(&dropped),
+			settings: None,
+			file: None,
+		};
+		std::thread::Builder::new()
+			.name("access-log".into())
+			.spawn(move || writer.run(receiver))
+			.expect("failed to start the access log thread");
+		AccessLogger { sender, dropped }
+	}
+
+	fn send(&self, message: Message) {
+		if self.sender.try_send(message).is_err() {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Closes and opens the log file again, once an external tool has moved it.
+	pub fn reopen(&self) {
+		self.send(Message::Reopen);
+	}
+}
+
+/// Reopens the access log file on `SIGUSR1`.
+#[cfg(unix)]
+pub fn create_reopen_task(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+	use tokio::signal::unix::{signal, SignalKind};
+	tokio::task::spawn(async move {
+		let mut signal = match signal(SignalKind::user_defined1()) {
+			Ok(signal) => signal,
+			Err(e) => {
+				log::error!("failed to listen for SIGUSR1: {}", e);
+				return;
+			}
+		};
+		while signal.recv().await.is_some() {
+			log::info!("SIGUSR1 received, reopening the access log");
+			state.access_log.reopen();
+		}
+	})
+}
+
+#[cfg(not(unix))]
+pub fn create_reopen_task(_state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+	tokio::task::spawn(async {})
+}
+
+/// What the access log, the metrics and the trace need from a request, taken before it is
+/// handled.
+#[derive(Debug)]
+pub struct RequestInfo {
+	// filled in while the request is routed
+	pub route: RouteLabels,
+	// server span of the request when tracing is on, ended with the response body
+	pub span: Option<Span>,
+	// request body bytes read so far
+	bytes_in: Arc<AtomicU64>,
+	// counts the request as in flight until the response body is sent
+	_in_flight: InFlight,
+	time: OffsetDateTime,
+	started: Instant,
+	client_ip: IpAddr,
+	method: Method,
+	uri: String,
+	version: Version,
+	host: Option<String>,
+	referer: Option<String>,
+	user_agent: Option<String>,
+}
+
+impl RequestInfo {
+	pub fn new(req: &Request<Body>, client_ip: IpAddr) -> Self {
+		let header = |name| {
+			req.headers()
+				.get(name)
+				.map(|value: &hyper::header::HeaderValue| {
+					String::from_utf8_lossy(value.as_bytes()).into_owned()
+				})
+		};
+		RequestInfo {
+			route: RouteLabels::default(),
+			span: None,
+			bytes_in: Arc::new(AtomicU64::new(0)),
+			_in_flight: InFlight::start(),
+			time: OffsetDateTime::now_utc(),
+			started: Instant::now(),
+			client_ip,
+			method: req.method().clone(),
+			uri: req
+				.uri()
+				.path_and_query()
+				.map_or("/", |path| path.as_str())
+				.to_string(),
+			version: req.version(),
+			host: host::request_host(req),
+			referer: header(REFERER),
+			user_agent: header(USER_AGENT),
+		}
+	}
+
+	/// Counts the bytes of the request body as it is read. An empty body is left alone.
+	pub fn count_body(&self, req: Request<Body>) -> Request<Body> {
+		if req.body().is_end_stream() {
+			return req;
+		}
+		let bytes_in = Arc::clone(&self.bytes_in);
+		req.map(|body| {
+			Body::wrap_stream(body.inspect(move |chunk| {
+				if let Ok(chunk) = chunk {
+					bytes_in.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+				}
+			}))
+		})
+	}
+}
+
+/// One request as written to the log.
+#[derive(Debug)]
+struct Record {
+	request: RequestInfo,
+	status: StatusCode,
+	bytes: u64,
+	upstream: Option<UpstreamTiming>,
+	latency: Duration,
+	cache: Option<CacheStatus>,
+}
+
+// Counts its record in the metrics and logs it when dropped, i.e. once the body is sent or the
+// client went away
+struct Entry {
+	record: Record,
+	// without settings the access log is off
+	settings: Option<Arc<AccessLog>>,
+	logger: AccessLogger,
+}
+
+impl Drop for Entry {
+	fn drop(&mut self) {
+		let record = &mut self.record;
+		record.latency = record.request.started.elapsed();
+		METRICS.record_request(
+			&record.request.route,
+			&Completed {
+				status: record.status.as_u16(),
+				latency: record.latency,
+				upstream_latency: record.upstream.as_ref().map(|upstream| upstream.latency),
+				bytes_in: record.request.bytes_in.load(Ordering::Relaxed),
+				bytes_out: record.bytes,
+				cache: record.cache,
+			},
+		);
+		let request = &mut record.request;
+		if let Some(span) = &mut request.span {
+			match request.route.route.as_str() {
+				"" => span.set_name(request.method.to_string()),
+				route => {
+					span.set_name(format!("{} {}", request.method, route));
+					span.set("http.route", route);
+				}
+			}
+			span.set(
+				"http.response.status_code",
+				i64::from(record.status.as_u16()),
+			);
+			span.set("http.response.body.size", record.bytes as i64);
+			if record.status.is_server_error() {
+				span.set_error(record.status.to_string());
+			}
+		}
+		if let Some(settings) = &self.settings {
+			let line = format_record(record, settings);
+			self.logger.send(Message::Line(line, Arc::clone(settings)));
+		}
+	}
+}
+
+/// Logs the response of a request and counts it in the metrics once its body is fully sent or
+/// the client goes away.
+pub fn finish_response(
+	res: Response<Body>,
+	request: RequestInfo,
+	settings: Option<Arc<AccessLog>>,
+	logger: &AccessLogger,
+) -> Response<Body> {
+	let entry = Entry {
+		record: Record {
+			request,
+			status: res.status(),
+			bytes: 0,
+			upstream: res.extensions().get::<UpstreamTiming>().cloned(),
+			latency: Duration::ZERO,
+			cache: control_headers::cache_status(res.status(), res.headers()),
+		},
+		settings,
+		logger: logger.clone(),
+	};
+	if res.body().is_end_stream() {
+		// nothing to send, logged right away
+		return res;
+	}
+	let (mut parts, body) = res.into_parts();
+	if let Some(length) = HttpBody::size_hint(&body).exact() {
+		// a wrapped body has no known length, hyper would send it chunked
+		parts.headers.entry(CONTENT_LENGTH).or_insert(length.into());
+	}
+	Response::from_parts(parts, Body::wrap_stream(CountingBody { body, entry }))
+}
+
+struct CountingBody {
+	body: Body,
+	entry: Entry,
+}
+
+impl Stream for CountingBody {
+	type Item = Result<Bytes, hyper::Error>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let poll = Pin::new(&mut self.body).poll_next(cx);
+		if let Poll::Ready(Some(Ok(chunk))) = &poll {
+			self.entry.record.bytes += chunk.len() as u64;
+		}
+		poll
+	}
+}
+
+fn format_record(record: &Record, settings: &AccessLog) -> String {
+	match settings.format {
+		AccessLogFormat::Json => json_line(record, &settings.fields),
+		_ => combined_line(record),
+	}
+}
+
+// `ip - - [time] "request" status bytes "referer" "user agent"`
+fn combined_line(record: &Record) -> String {
+	let request = &record.request;
+	let time = request
+		.time
+		.format(format_description!(
+			"[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+		))
+		.unwrap_or_default();
+	let bytes = match record.bytes {
+		0 => "-".to_string(),
+		bytes => bytes.to_string(),
+	};
+	let quoted = |value: &Option<String>| value.as_deref().map_or("-".to_string(), escape);
+	format!(
+		"{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\"",
+		request.client_ip,
+		time,
+		request.method,
+		escape(&request.uri),
+		request.version,
+		record.status.as_u16(),
+		bytes,
+		quoted(&request.referer),
+		quoted(&request.user_agent)
+	)
+}
+
+// Quotes, backslashes and non printable bytes as `\xHH`, like nginx
+fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		if byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte) {
+			let _ = write!(escaped, "\\x{:02X}", byte);
+		} else {
+			escaped.push(byte as char);
+		}
+	}
+	escaped
+}
+
+fn json_line(record: &Record, fields: &[AccessLogField]) -> String {
+	let fields = if fields.is_empty() {
+		&AccessLogField::ALL[..]
+	} else {
+		fields
+	};
+	let request = &record.request;
+	let millis = |duration: Duration| (duration.as_secs_f64() * 1e6).round() / 1e3;
+	let mut line = String::from("{");
+	for (position, field) in fields.iter().enumerate() {
+		let value = match field {
+			AccessLogField::Time => serde_json::json!(request.time.format(&Rfc3339).ok()),
+			AccessLogField::ClientIp => serde_json::json!(request.client_ip),
+			AccessLogField::Method => serde_json::json!(request.method.as_str()),
+			AccessLogField::Uri => serde_json::json!(request.uri),
+			AccessLogField::Protocol => serde_json::json!(format!("{:?}", request.version)),
+			AccessLogField::Status => serde_json::json!(record.status.as_u16()),
+			AccessLogField::Bytes => serde_json::json!(record.bytes),
+			AccessLogField::Host => serde_json::json!(request.host),
+			AccessLogField::Referer => serde_json::json!(request.referer),
+			AccessLogField::UserAgent => serde_json::json!(request.user_agent),
+			AccessLogField::Upstream => {
+				serde_json::json!(record.upstream.as_ref().map(|upstream| &upstream.url))
+			}
+			AccessLogField::UpstreamLatency => serde_json::json!(record
+				.upstream
+				.as_ref()
+				.map(|upstream| millis(upstream.latency))),
+			AccessLogField::Latency => serde_json::json!(millis(record.latency)),
+		};
+		if position > 0 {
+			line.push(',');
+		}
+		let name = serde_json::to_string(field).unwrap_or_default();
+		let _ = write!(line, "{}:{}", name, value);
+	}
+	line.push('}');
+	line
+}
+
+// Owns the output, on its own thread
+struct Writer {
+	dropped: Arc<AtomicU64>,
+	// settings the output was opened with
+	settings: Option<Arc<AccessLog>>,
+	file: Option<LogFile>,
+}
+
+impl Writer {
+	fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+		while let Some(message) = receiver.blocking_recv() {
+			self.handle(message);
+			// write whatever is queued, then flush once
+			while let Ok(message) = receiver.try_recv() {
+				self.handle(message);
+			}
+			if let Some(file) = &mut self.file {
+				if let Err(e) = file.writer.flush() {
+					log::error!("failed to write {}: {}", file.path.display(), e);
+				}
+			}
+			let dropped = self.dropped.swap(0, Ordering::Relaxed);
+			if dropped > 0 {
+				log::warn!("access log queue full, dropped {} lines", dropped);
+			}
+		}
+	}
+
+	fn handle(&mut self, message: Message) {
+		match message {
+			Message::Reopen => {
+				// opened again by the next line
+				self.file = None;
+			}
+			Message::Line(line, settings) => {
+				let same = self.settings.as_ref().is_some_and(|current| {
+					Arc::ptr_eq(current, &settings) || **current == *settings
+				});
+				if !same {
+					if self.settings.as_ref().map(|current| &current.file) != Some(&settings.file) {
+						self.file = None;
+					}
+					self.settings = Some(settings);
+				}
+				self.write(&line);
+			}
+		}
+	}
+
+	fn write(&mut self, line: &str) {
+		let Some(settings) = &self.settings else {
+			return;
+		};
+		let Some(path) = &settings.file else {
+			println!("{}", line);
+			return;
+		};
+		if self.file.is_none() {
+			self.file = LogFile::open(Path::new(path), settings);
+		}
+		let Some(file) = &mut self.file else {
+			return;
+		};
+		if file.needs_rotation(line.len() as u64 + 1, settings) {
+			let path = file.path.clone();
+			// flushed and closed before it is renamed
+			self.file = None;
+			if let Err(e) = rotate(&path, settings.max_files) {
+				log::error!("failed to rotate {}: {}", path.display(), e);
+			}
+			self.file = LogFile::open(&path, settings);
+		}
+		let Some(file) = &mut self.file else {
+			return;
+		};
+		match writeln!(file.writer, "{}", line) {
+			Ok(()) => file.size += line.len() as u64 + 1,
+			Err(e) => log::error!("failed to write {}: {}", file.path.display(), e),
+		}
+	}
+}
+
+struct LogFile {
+	path: PathBuf,
+	writer: BufWriter<File>,
+	size: u64,
+	// rotation period the file was started in
+	period: Option<u64>,
+}
+
+impl LogFile {
+	fn open(path: &Path, settings: &AccessLog) -> Option<Self> {
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.and_then(|file| Ok((file.metadata()?, file)));
+		match file {
+			Ok((meta, file)) => {
+				let started = match meta.len() {
+					0 => SystemTime::now(),
+					_ => meta.modified().unwrap_or_else(|_| SystemTime::now()),
+				};
+				Some(LogFile {
+					path: path.to_path_buf(),
+					writer: BufWriter::new(file),
+					size: meta.len(),
+					period: period(started, settings),
+				})
+			}
+			Err(e) => {
+				log::error!("failed to open access log {}: {}", path.display(), e);
+				None
+			}
+		}
+	}
+
+	fn needs_rotation(&self, incoming: u64, settings: &AccessLog) -> bool {
+		if self.size == 0 {
+			return false;
+		}
+		let too_large = settings
+			.max_size
+			.is_some_and(|max_size| self.size + incoming > max_size);
+		too_large || period(SystemTime::now(), settings) != self.period
+	}
+}
+
+fn period(time: SystemTime, settings: &AccessLog) -> Option<u64> {
+	let seconds = time
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	settings.rotate.map(|rotation| seconds / rotation.seconds())
+}
+
+// `file` becomes `file.1`, `file.1` becomes `file.2` and so on, dropping the oldest one
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+	let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+	match fs::remove_file(numbered(max_files)) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+		_ => {}
+	}
+	for n in (1..max_files).rev() {
+		if numbered(n).exists() {
+			fs::rename(numbered(n), numbered(n + 1))?;
+		}
+	}
+	fs::rename(path, numbered(1))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record() -> Record {
+		let req = Request::builder()
+			.uri("/search?q=a%20b")
+			.header("host", "example.com")
+			.header("user-agent", "curl/8.0 \"test\"")
+			.body(Body::empty())
+			.unwrap();
+		let mut request = RequestInfo::new(&req, "203.0.113.7".parse().unwrap());
+		request.time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+		Record {
+			request,
+			status: StatusCode::OK,
+			bytes: 512,
+			upstream: Some(UpstreamTiming {
+				url: "http://10.0.0.5:8080".into(),
+				latency: Duration::from_micros(12_345),
+			}),
+			latency: Duration::from_millis(20),
+			cache: None,
+		}
+	}
+
+	#[test]
+	fn formats_combined_lines() {
+		assert_eq!(
+			combined_line(&record()),
+			"203.0.113.7 - - [14/Nov/2023:22:13:20 +0000] \"GET /search?q=a%20b HTTP/1.1\" 200 512 \
+			 \"-\" \"curl/8.0 \\x22test\\x22\""
+		);
+	}
+
+	#[test]
+	fn formats_selected_json_fields() {
+		let line = json_line(
+			&record(),
+			&[
+				AccessLogField::Status,
+				AccessLogField::Upstream,
+				AccessLogField::UpstreamLatency,
+				AccessLogField::Referer,
+			],
+		);
+		assert_eq!(
+			line,
+			r#"{"status":200,"upstream":"http://10.0.0.5:8080","upstream_latency":12.345,"referer":null}"#
+		);
+		let all: serde_json::Value = serde_json::from_str(&json_line(&record(), &[])).unwrap();
+		assert_eq!(all["time"], "2023-11-14T22:13:20Z");
+		assert_eq!(all["latency"], 20.0);
+		assert_eq!(all.as_object().unwrap().len(), AccessLogField::ALL.len());
+	}
+
+	#[test]
+	fn rotates_numbered_files() {
+		let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("access.log");
+		for n in 0..4 {
+			fs::write(&path, n.to_string()).unwrap();
+			rotate(&path, 2).unwrap();
+		}
+		assert!(!path.exists());
+		assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "3");
+		assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "2");
+		assert!(!dir.join("access.log.3").exists());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,333 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{
+	header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+	service::{make_service_fn, service_fn},
+	Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+	config::Admin,
+	logging, metrics,
+	state::AppState,
+	usecase::{access::AdminEntry, circuit_breaker::CircuitState, health::HealthReport},
+	utils::redact::redact,
+};
+
+// Largest request body accepted by the admin endpoints
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Serialize)]
+struct UpstreamReport<'a> {
+	url: &'a str,
+	active_requests: usize,
+	drained: bool,
+	#[serde(flatten)]
+	health: HealthReport,
+	circuit: CircuitState,
+}
+
+#[derive(Debug, Serialize)]
+struct WhitelistReport {
+	// entries added through the admin API
+	entries: Vec<AdminEntry>,
+	// size of every configured list, `null` until it is loaded
+	lists: BTreeMap<String, Option<ListSize>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListSize {
+	allowed: usize,
+	denied: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryRequest {
+	entry: String,
+	// seconds before the entry is dropped, never when missing
+	#[serde(default)]
+	ttl: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamRequest {
+	url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogLevel {
+	log_level: String,
+}
+
+/// Serves the admin endpoints on their own listener.
+pub fn create_admin_task(state: Arc<AppState>, addr: SocketAddr) -> tokio::task::JoinHandle<()> {
+	tokio::task::spawn(async move {
+		let make_svc = make_service_fn(move |_| {
+			let state = Arc::clone(&state);
+			async move { Ok::<_, hyper::Error>(service_fn(move |req| handle(req, Arc::clone(&state)))) }
+		});
+
+		let server = match Server::try_bind(&addr) {
+			Ok(builder) => builder.serve(make_svc),
+			Err(e) => {
+				log::error!("failed to bind admin listener {}: {}", addr, e);
+				return;
+			}
+		};
+		log::info!("admin listening on {}", addr);
+
+		if let Err(e) = server.await {
+			log::error!("admin server error: {}", e);
+		}
+	})
+}
+
+async fn handle(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+	let admin = state.snapshot().config.admin.clone();
+	if let Some(res) = authorize(&req, admin.as_ref()) {
+		return Ok(res);
+	}
+
+	let res = match (req.method(), req.uri().path()) {
+		(&Method::GET, "/upstreams") => upstreams(&state),
+		(&Method::GET, "/metrics") => metrics::response(&state),
+		(&Method::POST, "/upstreams/drain") => drain(req, &state, true).await,
+		(&Method::POST, "/upstreams/enable") => drain(req, &state, false).await,
+		(&Method::GET, "/whitelist") => whitelist(&state),
+		(&Method::POST, "/whitelist") => add_entry(req, &state).await,
+		(&Method::DELETE, "/whitelist") => remove_entry(req, &state).await,
+		(&Method::POST, "/whitelist/refresh") => {
+			state.refresh_ip_lists.notify_one();
+			log::info!("admin: ip list refresh requested");
+			status(StatusCode::ACCEPTED, "refresh scheduled")
+		}
+		(&Method::GET, "/config") => config(&state),
+		(&Method::GET, "/log_level") => json(&LogLevel {
+			log_level: logging::level(),
+		}),
+		(&Method::PUT, "/log_level") => set_log_level(req).await,
+		_ => status(StatusCode::NOT_FOUND, "not found"),
+	};
+	Ok(res)
+}
+
+// Every endpoint needs the bearer token. Returns the response for a rejected request.
+fn authorize(req: &Request<Body>, admin: Option<&Admin>) -> Option<Response<Body>> {
+	// a reload may remove the admin block while the listener keeps running
+	let Some(token) = admin.map(|admin| &admin.token) else {
+		return Some(status(StatusCode::FORBIDDEN, "admin is disabled"));
+	};
+	let given = req
+		.headers()
+		.get(AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.unwrap_or_default();
+	if ring::constant_time::verify_slices_are_equal(given.trim().as_bytes(), token.as_bytes())
+		.is_ok()
+	{
+		return None;
+	}
+	let mut res = status(StatusCode::UNAUTHORIZED, "invalid or missing bearer token");
+	res.headers_mut()
+		.insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+	Some(res)
+}
+
+// Health of every upstream of the current configuration
+fn upstreams(state: &AppState) -> Response<Body> {
+	let snapshot = state.snapshot();
+	let mut reports: Vec<UpstreamReport> = snapshot
+		.upstreams
+		.values()
+		.map(|upstream| UpstreamReport {
+			url: &upstream.url,
+			active_requests: upstream.active(),
+			drained: upstream.is_drained(),
+			health: upstream.health.report(),
+			circuit: upstream.circuit.state(),
+		})
+		.collect();
+	reports.sort_by(|a, b| a.url.cmp(b.url));
+	json(&reports)
+}
+
+async fn drain(req: Request<Body>, state: &AppState, drained: bool) -> Response<Body> {
+	let request: UpstreamRequest = match read_json(req).await {
+		Ok(request) => request,
+		Err(res) => return res,
+	};
+	let snapshot = state.snapshot();
+	let Some(upstream) = snapshot.upstreams.get(&request.url) else {
+		return status(StatusCode::NOT_FOUND, "unknown upstream");
+	};
+	upstream.set_drained(drained);
+	let action = if drained { "drained" } else { "enabled" };
+	log::info!("admin: upstream {} {}", upstream.url, action);
+	status(StatusCode::OK, action)
+}
+
+fn whitelist(state: &AppState) -> Response<Body> {
+	let snapshot = state.snapshot();
+	let ip_lists = state.ip_lists.lock().unwrap();
+	let lists = snapshot
+		.config
+		.ip_list_sources()
+		.into_keys()
+		.map(|name| {
+			let size = ip_lists.get(&name).map(|list| ListSize {
+				allowed: list.allow.len(),
+				denied: list.deny.len(),
+			});
+			(name, size)
+		})
+		.collect();
+	drop(ip_lists);
+	json(&WhitelistReport {
+		entries: state.admin_entries(),
+		lists,
+	})
+}
+
+async fn add_entry(req: Request<Body>, state: &AppState) -> Response<Body> {
+	let request: EntryRequest = match read_json(req).await {
+		Ok(request) => request,
+		Err(res) => return res,
+	};
+	let ttl = request.ttl.map(Duration::from_secs);
+	match state.update_admin_entries(|entries| entries.add(&request.entry, ttl)) {
+		Ok(entry) => {
+			log::info!("admin: whitelist entry {} added, ttl {:?}", entry, ttl);
+			status(StatusCode::OK, "added")
+		}
+		Err(e) => status(StatusCode::BAD_REQUEST, &e),
+	}
+}
+
+async fn remove_entry(req: Request<Body>, state: &AppState) -> Response<Body> {
+	let request: EntryRequest = match read_json(req).await {
+		Ok(request) => request,
+		Err(res) => return res,
+	};
+	if state.update_admin_entries(|entries| entries.remove(&request.entry)) {
+		log::info!("admin: whitelist entry {} removed", request.entry.trim());
+		status(StatusCode::OK, "removed")
+	} else {
+		status(StatusCode::NOT_FOUND, "unknown entry")
+	}
+}
+
+async fn set_log_level(req: Request<Body>) -> Response<Body> {
+	let request: LogLevel = match read_json(req).await {
+		Ok(request) => request,
+		Err(res) => return res,
+	};
+	match logging::set_level(&request.log_level) {
+		Ok(()) => {
+			log::info!("admin: log level set to {:?}", logging::level());
+			json(&LogLevel {
+				log_level: logging::level(),
+			})
+		}
+		Err(e) => status(StatusCode::BAD_REQUEST, &e),
+	}
+}
+
+// The configuration in use, secrets redacted
+fn config(state: &AppState) -> Response<Body> {
+	let snapshot = state.snapshot();
+	match serde_json::to_value(&snapshot.config) {
+		Ok(mut value) => {
+			redact(&mut value);
+			json(&value)
+		}
+		Err(e) => status(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+	}
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+	let too_large = || status(StatusCode::PAYLOAD_TOO_LARGE, "body too large");
+	let length = hyper::body::HttpBody::size_hint(req.body()).lower() as usize;
+	if length > MAX_BODY {
+		return Err(too_large());
+	}
+	// stop reading as soon as the body gets too large
+	let mut body = req.into_body();
+	let mut buf = Vec::with_capacity(length);
+	while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+		let chunk = chunk.map_err(|e| status(StatusCode::BAD_REQUEST, &e.to_string()))?;
+		if buf.len() + chunk.len() > MAX_BODY {
+			return Err(too_large());
+		}
+		buf.extend_from_slice(&chunk);
+	}
+	serde_json::from_slice(&buf).map_err(|e| status(StatusCode::BAD_REQUEST, &e.to_string()))
+}
+
+// A JSON `{"status": ...}` or `{"error": ...}` message
+fn status(code: StatusCode, message: &str) -> Response<Body> {
+	let key = if code.is_success() { "status" } else { "error" };
+	let mut res = json(&serde_json::json!({ key: message }));
+	*res.status_mut() = code;
+	res
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+	match serde_json::to_vec_pretty(value) {
+		Ok(body) => Response::builder()
+			.header(CONTENT_TYPE, "application/json")
+			.body(body.into())
+			.unwrap(),
+		Err(e) => Response::builder()
+			.status(StatusCode::INTERNAL_SERVER_ERROR)
+			.body(e.to_string().into())
+			.unwrap(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn needs_token() {
+		let admin = Admin {
+			listen: "127.0.0.1:9000".into(),
+			token: "s3cret".into(),
+		};
+		let request = |auth: Option<&str>| {
+			let mut req = Request::get("/upstreams");
+			if let Some(auth) = auth {
+				req = req.header(AUTHORIZATION, auth);
+			}
+			req.body(Body::empty()).unwrap()
+		};
+
+		assert!(authorize(&request(Some("Bearer s3cret")), Some(&admin)).is_none());
+		let res = authorize(&request(Some("Bearer nope")), Some(&admin)).unwrap();
+		assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+		let res = authorize(&request(None), Some(&admin)).unwrap();
+		assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+		let res = authorize(&request(Some("Bearer s3cret")), None).unwrap();
+		assert_eq!(res.status(), StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn caps_streamed_body() {
+		let (mut sender, body) = Body::channel();
+		tokio::spawn(async move {
+			let chunk = vec![b' '; MAX_BODY / 2];
+			while sender.send_data(chunk.clone().into()).await.is_ok() {}
+		});
+		let req = Request::post("/whitelist").body(body).unwrap();
+		let res = read_json::<EntryRequest>(req).await.unwrap_err();
+		assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+		let req = Request::post("/whitelist")
+			.body(r#"{"entry": "203.0.113.0/24"}"#.into())
+			.unwrap();
+		let entry = read_json::<EntryRequest>(req).await.unwrap();
+		assert_eq!(entry.entry, "203.0.113.0/24");
+	}
+}
@@ -1,46 +1,1523 @@
-use std::collections::HashMap;
+use std::{
+	collections::{BTreeMap, HashMap},
+	env, fs,
+	net::{SocketAddr, ToSocketAddrs},
+	path::PathBuf,
+	time::Duration,
+};
 
+use hyper::{
+	header::{HeaderName, HeaderValue},
+	Uri,
+};
+use rustls::ServerName;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::{
+	prelude::*,
+	utils::ip_list::{IpList, IpTrie},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RequestHeader {
 	#[serde(flatten)]
 	pub headers: Option<HashMap<String, String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Proxy {
+	// single upstream, used when `upstreams` is empty
+	#[serde(default)]
 	pub proxy_pass: String,
 	pub proxy_path: String,
+	// how proxy_path is compared with the request path
+	#[serde(default)]
+	pub match_type: MatchType,
 	pub retain_path: bool,
 	#[serde(default)]
 	pub request_headers: Option<Vec<RequestHeader>>,
+	// regex rewrites of the upstream path, the first matching rule applies
+	#[serde(default)]
+	pub rewrite: Vec<Rewrite>,
+	#[serde(default)]
+	pub query: QueryRewrite,
+	// upstream connection pool
+	#[serde(default)]
+	pub pool_max_idle_per_host: Option<usize>,
+	// seconds an idle upstream connection is kept alive
+	#[serde(default)]
+	pub pool_idle_timeout: Option<u64>,
+	#[serde(default)]
+	pub http_version: HttpVersion,
+	// several upstreams sharing the traffic of this route
+	#[serde(default)]
+	pub upstreams: Vec<Upstream>,
+	#[serde(default)]
+	pub load_balancing: LoadBalancing,
+	// what `consistent_hash` hashes on
+	#[serde(default)]
+	pub hash_key: HashKey,
+	// periodic probes of every upstream
+	#[serde(default)]
+	pub health_check: Option<HealthCheck>,
+	// ejects upstreams failing live requests
+	#[serde(default)]
+	pub passive_health_check: Option<PassiveHealthCheck>,
+	// overrides the global `timeouts`
+	#[serde(default)]
+	pub timeouts: Timeouts,
+	// retries of failed upstream requests
+	#[serde(default)]
+	pub retry: Option<Retry>,
+	// stops sending traffic to failing upstreams for a while
+	#[serde(default)]
+	pub circuit_breaker: Option<CircuitBreaker>,
+	// reject requests without a verified client certificate, see `tls.client_auth`
+	#[serde(default)]
+	pub require_client_cert: bool,
+	// TLS settings of https upstreams
+	#[serde(default)]
+	pub upstream_tls: Option<UpstreamTls>,
+	// client addresses allowed on this route
+	#[serde(default)]
+	pub access: Option<AccessPolicy>,
+}
+
+impl Proxy {
+	/// URLs of every upstream of this proxy.
+	pub fn upstream_urls(&self) -> Vec<&str> {
+		if self.upstreams.is_empty() {
+			vec![self.proxy_pass.as_str()]
+		} else {
+			self.upstreams
+				.iter()
+				.map(|upstream| upstream.url.as_str())
+				.collect()
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Upstream {
+	pub url: String,
+	#[serde(default = "default_weight")]
+	pub weight: u32,
+	// only receives traffic when every non backup upstream is unavailable
+	#[serde(default)]
+	pub backup: bool,
+}
+
+fn default_weight() -> u32 {
+	1
+}
+
+/// Highest `weight` of an upstream, bounding the points it takes on a hash ring.
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// Upstream timeouts in seconds, `0` disabling one. Unset values of a proxy come from the global
+/// `timeouts`, then from the built-in defaults.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Timeouts {
+	// establishing the connection (default 10)
+	#[serde(default)]
+	pub connect: Option<u64>,
+	// from sending the request to receiving the response headers (default 60)
+	#[serde(default)]
+	pub first_byte: Option<u64>,
+	// whole exchange, response body included (disabled by default)
+	#[serde(default)]
+	pub total: Option<u64>,
+	// between two chunks of the response body (default 60)
+	#[serde(default)]
+	pub idle_body: Option<u64>,
+}
+
+impl Timeouts {
+	/// Fills the unset values from `defaults`.
+	pub fn or(&self, defaults: &Timeouts) -> Timeouts {
+		Timeouts {
+			connect: self.connect.or(defaults.connect),
+			first_byte: self.first_byte.or(defaults.first_byte),
+			total: self.total.or(defaults.total),
+			idle_body: self.idle_body.or(defaults.idle_body),
+		}
+	}
+}
+
+/// Retry policy of a proxy. Only idempotent methods are retried unless `non_idempotent` is set.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Retry {
+	// attempts in total, the first one included
+	#[serde(default = "default_retry_attempts")]
+	pub attempts: u32,
+	// failures worth another attempt
+	#[serde(default = "default_retry_on")]
+	pub on: Vec<RetryOn>,
+	// upstream statuses worth another attempt
+	#[serde(default)]
+	pub statuses: Vec<u16>,
+	// milliseconds, doubled on every retry then randomized
+	#[serde(default = "default_retry_backoff")]
+	pub backoff: u64,
+	// milliseconds
+	#[serde(default = "default_retry_max_backoff")]
+	pub max_backoff: u64,
+	// also retry POST and PATCH requests
+	#[serde(default)]
+	pub non_idempotent: bool,
+	// bytes of request body buffered to be sent again, larger bodies are not retried
+	#[serde(default = "default_retry_max_body_size")]
+	pub max_body_size: usize,
+}
+
+fn default_retry_attempts() -> u32 {
+	3
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+	vec![RetryOn::ConnectError, RetryOn::Error]
+}
+
+fn default_retry_backoff() -> u64 {
+	50
+}
+
+fn default_retry_max_backoff() -> u64 {
+	1000
+}
+
+fn default_retry_max_body_size() -> usize {
+	64 * 1024
+}
+
+/// Upstream failures a request can be retried on.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+	/// The connection could not be established, connect timeouts included
+	ConnectError,
+	/// The connection failed before the response headers, e.g. it was reset
+	Error,
+	/// No response headers within the first byte timeout
+	Timeout,
+}
+
+/// Circuit breaker settings of the upstreams of a proxy.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CircuitBreaker {
+	// seconds of requests the error rate is computed over
+	#[serde(default = "default_circuit_window")]
+	pub window: u64,
+	// requests in the window before the error rate is considered
+	#[serde(default = "default_circuit_min_requests")]
+	pub min_requests: u32,
+	// percentage of failed requests opening the circuit
+	#[serde(default = "default_circuit_error_rate")]
+	pub error_rate: u32,
+	// milliseconds after which a response counts as failed
+	#[serde(default)]
+	pub slow_call: Option<u64>,
+	// seconds the circuit stays open
+	#[serde(default = "default_circuit_open_duration")]
+	pub open_duration: u64,
+	// trial requests, all successful, needed to close the circuit again
+	#[serde(default = "default_circuit_half_open_requests")]
+	pub half_open_requests: u32,
+	// file under the server root sent instead of the plain 503 while the circuit is open
+	#[serde(default)]
+	pub fallback: Option<String>,
+}
+
+fn default_circuit_window() -> u64 {
+	30
+}
+
+fn default_circuit_min_requests() -> u32 {
+	20
+}
+
+fn default_circuit_error_rate() -> u32 {
+	50
+}
+
+fn default_circuit_open_duration() -> u64 {
+	30
+}
+
+fn default_circuit_half_open_requests() -> u32 {
+	1
+}
+
+impl CircuitBreaker {
+	pub fn open_duration(&self) -> Duration {
+		Duration::from_secs(self.open_duration)
+	}
+}
+
+/// Active probing of the upstreams of a proxy.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+	#[serde(default = "default_health_path")]
+	pub path: String,
+	// seconds between probes
+	#[serde(default = "default_health_interval")]
+	pub interval: u64,
+	// seconds to wait for the probe response
+	#[serde(default = "default_health_timeout")]
+	pub timeout: u64,
+	// any 2xx status when empty
+	#[serde(default)]
+	pub expected_status: Vec<u16>,
+	// consecutive successful probes before an unhealthy upstream gets traffic again
+	#[serde(default = "default_healthy_threshold")]
+	pub healthy_threshold: u32,
+	// consecutive failed probes before an upstream stops getting traffic
+	#[serde(default = "default_unhealthy_threshold")]
+	pub unhealthy_threshold: u32,
+}
+
+fn default_health_path() -> String {
+	"/".to_string()
+}
+
+fn default_health_interval() -> u64 {
+	10
+}
+
+fn default_health_timeout() -> u64 {
+	2
+}
+
+fn default_healthy_threshold() -> u32 {
+	2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+	3
+}
+
+impl HealthCheck {
+	pub fn expects(&self, status: u16) -> bool {
+		if self.expected_status.is_empty() {
+			(200..300).contains(&status)
+		} else {
+			self.expected_status.contains(&status)
+		}
+	}
+}
+
+/// Ejection of upstreams failing live requests with connection errors or 5xx responses.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct PassiveHealthCheck {
+	// consecutive failures before the upstream is ejected
+	#[serde(default = "default_max_failures")]
+	pub max_failures: u32,
+	// seconds the upstream is ejected for
+	#[serde(default = "default_ejection_time")]
+	pub ejection_time: u64,
+}
+
+fn default_max_failures() -> u32 {
+	5
+}
+
+fn default_ejection_time() -> u64 {
+	30
+}
+
+/// How requests are spread over the upstreams of a proxy.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+	#[default]
+	RoundRobin,
+	WeightedRoundRobin,
+	LeastConnections,
+	/// Picks two upstreams at random and uses the least loaded one
+	RandomTwoChoices,
+	/// Sends requests with the same `hash_key` to the same upstream
+	ConsistentHash,
+}
+
+/// Request value hashed by `consistent_hash`: `client_ip`, `header:<name>` or `fp_visitor`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum HashKey {
+	#[default]
+	ClientIp,
+	Header(String),
+	/// Visitor id of the fingerprintjs `_fp_id` cookie
+	FpVisitor,
+}
+
+impl TryFrom<String> for HashKey {
+	type Error = String;
+
+	fn try_from(value: String) -> core::result::Result<Self, Self::Error> {
+		match value.as_str() {
+			"client_ip" => Ok(HashKey::ClientIp),
+			"fp_visitor" => Ok(HashKey::FpVisitor),
+			_ => match value.strip_prefix("header:") {
+				Some(name) if !name.is_empty() => Ok(HashKey::Header(name.to_string())),
+				_ => Err(format!(
+					"invalid hash_key {:?}, expected client_ip, fp_visitor or header:<name>",
+					value
+				)),
+			},
+		}
+	}
+}
+
+impl From<HashKey> for String {
+	fn from(key: HashKey) -> Self {
+		match key {
+			HashKey::ClientIp => "client_ip".to_string(),
+			HashKey::Header(name) => format!("header:{}", name),
+			HashKey::FpVisitor => "fp_visitor".to_string(),
+		}
+	}
+}
+
+/// Rewrites the upstream path, e.g. `{"pattern": "^/legacy/(.*)$", "replacement": "/v2/$1"}`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Rewrite {
+	pub pattern: String,
+	pub replacement: String,
+}
+
+/// Query string changes applied before a request is forwarded, in the order remove, rename, add.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct QueryRewrite {
+	// parameters to set, replacing existing ones with the same name
+	#[serde(default)]
+	pub add: BTreeMap<String, String>,
+	#[serde(default)]
+	pub remove: Vec<String>,
+	// old name to new name
+	#[serde(default)]
+	pub rename: BTreeMap<String, String>,
+}
+
+/// How a proxy's `proxy_path` is matched against the request path.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchType {
+	/// Whole path segments, `/api` matches `/api` and `/api/users` but not `/apiary`
+	#[default]
+	Prefix,
+	/// The path must be equal to `proxy_path`
+	Exact,
+	/// `proxy_path` is a regular expression, named groups are available as `$name`
+	Regex,
+}
+
+/// How https upstreams are verified and what the proxy presents to them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+pub struct UpstreamTls {
+	// PEM bundle of the CAs trusted instead of the system ones
+	#[serde(default)]
+	pub ca: Option<String>,
+	// client certificate chain and private key, PEM files
+	#[serde(default)]
+	pub cert: Option<String>,
+	#[serde(default)]
+	pub key: Option<String>,
+	// SNI name and name the certificate is checked against, instead of the URL host
+	#[serde(default)]
+	pub server_name: Option<String>,
+	#[serde(default)]
+	pub min_version: TlsVersion,
+	// accept any certificate, for local development only
+	#[serde(default)]
+	pub dangerous_skip_verify: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TlsVersion {
+	#[default]
+	#[serde(rename = "1.2")]
+	Tls12,
+	#[serde(rename = "1.3")]
+	Tls13,
+}
+
+/// HTTP version used to talk to an upstream.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+	/// HTTP/1.1 only
+	#[default]
+	Http1,
+	/// HTTP/2 only, negotiated through ALPN on https and prior knowledge on http
+	Http2,
+	/// HTTP/2 when the upstream offers it through ALPN, HTTP/1.1 otherwise
+	Auto,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Server {
 	pub root: String,
 	pub fingerprintjs: Option<String>,
 	pub name: String,
+	// host names served by this block, `name` is used when empty
+	#[serde(default)]
+	pub server_names: Vec<String>,
+	// serve requests whose host matches no server block
+	#[serde(default)]
+	pub default_server: bool,
 	pub proxies: Vec<Proxy>,
-	pub listen: String,
+	pub listen: Listen,
+	// body of the 502 and 504 responses sent when an upstream fails
+	#[serde(default)]
+	pub error_page: ErrorPage,
+	// HTTPS on every address in `listen`
+	#[serde(default)]
+	pub tls: Option<Tls>,
+	// client addresses allowed on this server block
+	#[serde(default)]
+	pub access: Option<AccessPolicy>,
+}
+
+/// Client addresses allowed at one level: the whole configuration, a server block or a proxy.
+///
+/// A request must pass the policies of every level it goes through, unless one sets
+/// `inherit` to false, which drops the policies above it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AccessPolicy {
+	// addresses and CIDR ranges, `!` denies an entry
+	#[serde(default)]
+	pub allow: Vec<String>,
+	#[serde(default)]
+	pub deny: Vec<String>,
+	// names of `ip_lists` whose entries are added to `allow` and `deny`
+	#[serde(default)]
+	pub lists: Vec<String>,
+	#[serde(default = "default_inherit")]
+	pub inherit: bool,
+	// response sent to the clients this policy rejects
+	#[serde(default)]
+	pub forbidden: Option<Forbidden>,
+}
+
+fn default_inherit() -> bool {
+	true
+}
+
+impl AccessPolicy {
+	/// `allow` and `deny` as one list.
+	pub fn ip_list(&self) -> Result<IpList> {
+		let mut list = IpList::default();
+		for entry in &self.allow {
+			list.add(entry.trim())
+				.map_err(|e| Error::Generic(f!("access: {}", e)))?;
+		}
+		for entry in &self.deny {
+			list.add(&f!("!{}", entry.trim().trim_start_matches('!')))
+				.map_err(|e| Error::Generic(f!("access: {}", e)))?;
+		}
+		Ok(list)
+	}
+
+	fn validate(&self, ip_lists: &BTreeMap<String, IpListSource>) -> Result<()> {
+		self.ip_list()?;
+		if let Some(name) = self.lists.iter().find(|name| !ip_lists.contains_key(*name)) {
+			return Err(Error::Generic(f!(
+				"access: unknown ip_lists entry {name:?}"
+			)));
+		}
+		if let Some(forbidden) = &self.forbidden {
+			HeaderValue::from_str(&forbidden.content_type).map_err(|_| {
+				Error::Generic(f!(
+					"access: invalid content_type {:?}",
+					forbidden.content_type
+				))
+			})?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Forbidden {
+	pub body: String,
+	#[serde(default = "default_forbidden_content_type")]
+	pub content_type: String,
+}
+
+fn default_forbidden_content_type() -> String {
+	"text/plain; charset=utf-8".into()
+}
+
+/// IP list refreshed by the whitelist updater, from an http(s) URL or a local file.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct IpListSource {
+	#[serde(default)]
+	pub url: Option<String>,
+	#[serde(default)]
+	pub file: Option<String>,
+	#[serde(default)]
+	pub format: IpListFormat,
+	// lists with fewer entries are rejected and the previous one kept
+	#[serde(default = "default_min_entries")]
+	pub min_entries: usize,
+}
+
+fn default_min_entries() -> usize {
+	1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IpListFormat {
+	/// One entry per line, `#` starting a comment line
+	#[default]
+	Lines,
+	/// JSON array of strings
+	Json,
+}
+
+/// Name under which the `ip_whitelist_url` list is kept, next to the `ip_lists`.
+pub const WHITELIST_LIST: &str = "ip_whitelist_url";
+
+/// Name under which the entries added through the admin API are kept, next to the `ip_lists`.
+pub const ADMIN_LIST: &str = "admin_entries";
+
+/// Certificate of a server block, PEM files with the certificate chain and the private key.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tls {
+	pub cert: String,
+	pub key: String,
+	// plain HTTP addresses redirecting to HTTPS
+	#[serde(default)]
+	pub redirect_listen: Option<Listen>,
+	// verification of client certificates
+	#[serde(default)]
+	pub client_auth: Option<ClientAuth>,
+}
+
+/// Client certificates checked against a CA bundle, with the verified certificate forwarded to
+/// upstreams in request headers. A header set to `null` is not sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientAuth {
+	// PEM file with the CA certificates client certificates must chain to
+	pub ca: String,
+	#[serde(default)]
+	pub mode: ClientAuthMode,
+	// subject distinguished name
+	#[serde(default = "default_subject_header")]
+	pub subject_header: Option<String>,
+	// subject alternative names, comma separated
+	#[serde(default = "default_san_header")]
+	pub san_header: Option<String>,
+	// SHA-256 of the certificate, hex encoded
+	#[serde(default = "default_fingerprint_header")]
+	pub fingerprint_header: Option<String>,
+}
+
+fn default_subject_header() -> Option<String> {
+	Some("X-Client-Subject".into())
+}
+
+fn default_san_header() -> Option<String> {
+	Some("X-Client-SAN".into())
+}
+
+fn default_fingerprint_header() -> Option<String> {
+	Some("X-Client-Fingerprint".into())
+}
+
+impl ClientAuth {
+	pub fn headers(&self) -> [Option<&str>; 3] {
+		[
+			self.subject_header.as_deref(),
+			self.san_header.as_deref(),
+			self.fingerprint_header.as_deref(),
+		]
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+	/// Handshakes without a valid client certificate fail
+	#[default]
+	Required,
+	/// Clients may connect without a certificate, routes with `require_client_cert` reject them
+	Optional,
+}
+
+/// Body of the responses sent when an upstream fails, e.g.
+/// `{"format": "html", "file": "errors/{status}.html"}` with the file under `root`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum ErrorPage {
+	#[default]
+	Plain,
+	/// RFC 7807 `application/problem+json`
+	#[serde(rename = "problem+json")]
+	ProblemJson,
+	Html {
+		file: String,
+	},
+}
+
+impl Server {
+	/// Host names (or wildcard patterns) this server block answers to.
+	pub fn host_names(&self) -> Vec<&str> {
+		if self.server_names.is_empty() {
+			vec![self.name.as_str()]
+		} else {
+			self.server_names.iter().map(String::as_str).collect()
+		}
+	}
+}
+
+/// Addresses a server block listens on, either a single entry or a list of them.
+///
+/// Each entry is a bare port (`"3400"`, bound on `0.0.0.0`), `*:port`, `host:port` or `[ipv6]:port`.
+/// A single string may also hold several comma separated entries.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Listen {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl Default for Listen {
+	fn default() -> Self {
+		Listen::One(String::new())
+	}
+}
+
+impl Listen {
+	/// Resolves every entry into the socket addresses to bind, failing when there is none.
+	pub fn addresses(&self) -> Result<Vec<SocketAddr>> {
+		let entries: Vec<&str> = match self {
+			Listen::One(value) => value.split(',').map(str::trim).collect(),
+			Listen::Many(values) => values.iter().map(|value| value.trim()).collect(),
+		};
+
+		let mut addresses = Vec::new();
+		for entry in entries.into_iter().filter(|entry| !entry.is_empty()) {
+			for address in parse_listen_entry(entry)? {
+				if !addresses.contains(&address) {
+					addresses.push(address);
+				}
+			}
+		}
+
+		if addresses.is_empty() {
+			return Err(Error::Generic(
+				"at least one listen address is required".into(),
+			));
+		}
+		Ok(addresses)
+	}
+}
+
+fn parse_listen_entry(entry: &str) -> Result<Vec<SocketAddr>> {
+	if let Ok(port) = entry.parse::<u16>() {
+		return Ok(vec![SocketAddr::from(([0, 0, 0, 0], port))]);
+	}
+	if let Some(port) = entry.strip_prefix("*:") {
+		let port = port
+			.parse::<u16>()
+			.map_err(|_| Error::Generic(f!("Invalid port in listen address {entry:?}")))?;
+		return Ok(vec![SocketAddr::from(([0, 0, 0, 0], port))]);
+	}
+	if let Ok(address) = entry.parse::<SocketAddr>() {
+		return Ok(vec![address]);
+	}
+	// host names such as `localhost:3400`
+	let addresses: Vec<SocketAddr> = entry
+		.to_socket_addrs()
+		.map_err(|e| Error::Generic(f!("Invalid listen address {entry:?}: {e}")))?
+		.collect();
+	if addresses.is_empty() {
+		return Err(Error::Generic(f!(
+			"Listen address {entry:?} resolved to nothing"
+		)));
+	}
+	Ok(addresses)
+}
+
+/// What to do with a request whose host matches no server block and no `default_server` is set.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownHost {
+	/// Serve it with the first server block
+	#[default]
+	FirstServer,
+	/// Reply `421 Misdirected Request`
+	Misdirected,
+	/// Reply `404 Not Found`
+	NotFound,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Http {
 	pub servers: Vec<Server>,
+	#[serde(default)]
+	pub unknown_host: UnknownHost,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Configuration {
 	// optional fields
+	// env_logger filter, e.g. `warn,rust_reverse_proxy::usecase::proxy=debug`, RUST_LOG or
+	// `info` when empty
 	#[serde(default)]
 	pub log_level: String,
 	#[serde(default)]
+	pub log_format: LogFormat,
+	// prefix text lines with the time, JSON lines always have it
+	#[serde(default)]
+	pub log_timestamps: bool,
+	// one line per request, off when missing
+	#[serde(default)]
+	pub access_log: Option<AccessLogSetting>,
+	#[serde(default)]
 	pub ip_check_interval: String,
 	#[serde(default)]
 	pub ip_whitelist_url: String,
+	// addresses and CIDR ranges, comma separated, `!` denies an entry
 	#[serde(default)]
 	pub default_ip_whitelist: String,
+	// addresses and CIDR ranges always denied, comma separated
+	#[serde(default)]
+	pub default_ip_denylist: String,
+	// named lists referenced by access policies, refreshed every `ip_check_interval`
+	#[serde(default)]
+	pub ip_lists: BTreeMap<String, IpListSource>,
+	// directory keeping the last good version of every list across restarts
+	#[serde(default)]
+	pub ip_list_cache: Option<String>,
+	// client addresses allowed on every server block, with the lists above
+	#[serde(default)]
+	pub access: Option<AccessPolicy>,
+	// addresses and CIDR ranges of the proxies in front of this one
+	#[serde(default)]
+	pub trusted_proxies: Vec<String>,
+	// where trusted proxies put the client address
+	#[serde(default)]
+	pub client_ip_header: ClientIpHeader,
+	#[serde(default)]
+	pub admin: Option<Admin>,
+	// path serving the Prometheus metrics on every server block, e.g. `/metrics`, besides the
+	// admin listener
+	#[serde(default)]
+	pub metrics_path: Option<String>,
+	// OpenTelemetry traces of every request, off when missing
+	#[serde(default)]
+	pub tracing: Option<Tracing>,
+	// upstream timeouts of every proxy
+	#[serde(default)]
+	pub timeouts: Timeouts,
 	pub http: Http,
 }
+
+/// Header holding the client address, set by the `trusted_proxies`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientIpHeader {
+	#[default]
+	#[serde(rename = "x-forwarded-for")]
+	XForwardedFor,
+	/// RFC 7239
+	#[serde(rename = "forwarded")]
+	Forwarded,
+	#[serde(rename = "x-real-ip")]
+	XRealIp,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	/// one JSON object per line
+	Json,
+}
+
+/// `access_log`: a format name, or the full settings.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AccessLogSetting {
+	Format(AccessLogFormat),
+	Custom(AccessLog),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+	Off,
+	/// Apache/nginx combined log format
+	#[default]
+	Combined,
+	/// one JSON object per line, `verbose` in older files
+	#[serde(alias = "verbose")]
+	Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AccessLog {
+	#[serde(default)]
+	pub format: AccessLogFormat,
+	// fields of the `json` format, in order, every field when empty
+	#[serde(default)]
+	pub fields: Vec<AccessLogField>,
+	// file the lines are appended to, stdout when missing
+	#[serde(default)]
+	pub file: Option<String>,
+	// rotate the file once it reaches this many bytes
+	#[serde(default)]
+	pub max_size: Option<u64>,
+	// rotate the file every hour or every day, UTC
+	#[serde(default)]
+	pub rotate: Option<Rotation>,
+	// rotated files kept as `file.1` (newest) to `file.N`
+	#[serde(default = "default_max_files")]
+	pub max_files: usize,
+}
+
+impl Default for AccessLog {
+	fn default() -> Self {
+		AccessLog {
+			format: AccessLogFormat::default(),
+			fields: Vec::new(),
+			file: None,
+			max_size: None,
+			rotate: None,
+			max_files: default_max_files(),
+		}
+	}
+}
+
+fn default_max_files() -> usize {
+	5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogField {
+	Time,
+	ClientIp,
+	Method,
+	/// path and query
+	Uri,
+	Protocol,
+	Status,
+	/// response body bytes sent
+	Bytes,
+	Host,
+	Referer,
+	UserAgent,
+	Upstream,
+	/// milliseconds until the upstream answered
+	UpstreamLatency,
+	/// milliseconds until the response was fully sent
+	Latency,
+}
+
+impl AccessLogField {
+	pub const ALL: [AccessLogField; 13] = [
+		AccessLogField::Time,
+		AccessLogField::ClientIp,
+		AccessLogField::Method,
+		AccessLogField::Uri,
+		AccessLogField::Protocol,
+		AccessLogField::Status,
+		AccessLogField::Bytes,
+		AccessLogField::Host,
+		AccessLogField::Referer,
+		AccessLogField::UserAgent,
+		AccessLogField::Upstream,
+		AccessLogField::UpstreamLatency,
+		AccessLogField::Latency,
+	];
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+	Hourly,
+	Daily,
+}
+
+impl Rotation {
+	pub fn seconds(self) -> u64 {
+		match self {
+			Rotation::Hourly => 3600,
+			Rotation::Daily => 86400,
+		}
+	}
+}
+
+/// Listener serving the admin endpoints, bound at startup.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Admin {
+	pub listen: String,
+	// bearer token required by every endpoint
+	pub token: String,
+}
+
+impl Admin {
+	pub fn address(&self) -> Result<SocketAddr> {
+		parse_listen_entry(self.listen.trim())?
+			.into_iter()
+			.next()
+			.ok_or_else(|| Error::Generic(f!("admin: no address in {:?}", self.listen)))
+	}
+}
+
+/// OpenTelemetry traces, exported to a collector over OTLP/HTTP with JSON encoding.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tracing {
+	// traces endpoint of the collector, e.g. `http://127.0.0.1:4318/v1/traces`
+	pub endpoint: String,
+	#[serde(default)]
+	pub protocol: OtlpProtocol,
+	#[serde(default = "default_service_name")]
+	pub service_name: String,
+	// share of new traces recorded, requests continuing a trace follow its sampled flag
+	#[serde(default = "default_sample_ratio")]
+	pub sample_ratio: f64,
+	// sent with every export, e.g. the API key of a hosted collector
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+}
+
+/// OTLP transport, named like `OTEL_EXPORTER_OTLP_PROTOCOL`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OtlpProtocol {
+	/// OTLP/HTTP with JSON encoding, the only one supported
+	#[default]
+	#[serde(rename = "http/json")]
+	HttpJson,
+	#[serde(rename = "http/protobuf")]
+	HttpProtobuf,
+	#[serde(rename = "grpc")]
+	Grpc,
+}
+
+fn default_service_name() -> String {
+	"rust-reverse-proxy".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+	1.0
+}
+
+impl Tracing {
+	fn validate(&self) -> Result<()> {
+		let valid_endpoint = self
+			.endpoint
+			.parse::<Uri>()
+			.is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")));
+		if !valid_endpoint {
+			return Err(Error::Generic(f!(
+				"tracing: endpoint {:?} is not an http(s) URL",
+				self.endpoint
+			)));
+		}
+		if self.protocol != OtlpProtocol::HttpJson {
+			return Err(Error::Generic(
+				"tracing: only the \"http/json\" protocol is supported".into(),
+			));
+		}
+		if !(0.0..=1.0).contains(&self.sample_ratio) {
+			return Err(Error::Generic(
+				"tracing: sample_ratio must be between 0 and 1".into(),
+			));
+		}
+		for (name, value) in &self.headers {
+			if name.parse::<HeaderName>().is_err() || value.parse::<HeaderValue>().is_err() {
+				return Err(Error::Generic(f!("tracing: invalid header {:?}", name)));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Where the configuration is read from.
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+	/// JSON held in the `CONFIG_SETTING` environment variable
+	Env,
+	/// JSON file on disk
+	File(PathBuf),
+}
+
+impl ConfigSource {
+	/// `CONFIG_SETTING` when it is set, `config.json` otherwise.
+	pub fn from_env() -> Self {
+		match env::var("CONFIG_SETTING") {
+			Ok(_) => ConfigSource::Env,
+			Err(_) => ConfigSource::File(PathBuf::from("config.json")),
+		}
+	}
+
+	/// Reads, parses and validates the configuration.
+	pub fn load(&self) -> Result<Configuration> {
+		let json = match self {
+			ConfigSource::Env => {
+				env::var("CONFIG_SETTING").map_err(|e| Error::Generic(f!("CONFIG_SETTING: {e}")))?
+			}
+			ConfigSource::File(path) => fs::read_to_string(path)?,
+		};
+		let config: Configuration = serde_json::from_str(&json)
+			.map_err(|e| Error::Generic(f!("JSON was not well-formatted: {e}")))?;
+		config.validate()?;
+		Ok(config)
+	}
+}
+
+impl Configuration {
+	/// `default_ip_whitelist` and `default_ip_denylist` as one list.
+	pub fn default_ip_list(&self) -> Result<IpList> {
+		let (mut list, invalid) = IpList::parse(&self.default_ip_whitelist);
+		if let Some(entry) = invalid.first() {
+			return Err(Error::Generic(f!(
+				"default_ip_whitelist: invalid IP or CIDR range {entry:?}"
+			)));
+		}
+		for entry in self.default_ip_denylist.split(',').map(str::trim) {
+			if entry.is_empty() {
+				continue;
+			}
+			let denied = f!("!{}", entry.trim_start_matches('!'));
+			list.add(&denied).map_err(|_| {
+				Error::Generic(f!(
+					"default_ip_denylist: invalid IP or CIDR range {entry:?}"
+				))
+			})?;
+		}
+		Ok(list)
+	}
+
+	pub fn trusted_proxy_list(&self) -> Result<IpTrie> {
+		let mut trusted = IpTrie::default();
+		for entry in &self.trusted_proxies {
+			trusted.insert(
+				entry
+					.trim()
+					.parse()
+					.map_err(|e| Error::Generic(f!("trusted_proxies: {}", e)))?,
+			);
+		}
+		Ok(trusted)
+	}
+
+	/// Every list the whitelist updater refreshes: the `ip_lists` and `ip_whitelist_url`.
+	pub fn ip_list_sources(&self) -> BTreeMap<String, IpListSource> {
+		let mut sources = self.ip_lists.clone();
+		if !self.ip_whitelist_url.is_empty() {
+			sources.insert(
+				WHITELIST_LIST.to_string(),
+				IpListSource {
+					url: Some(self.ip_whitelist_url.clone()),
+					min_entries: default_min_entries(),
+					..Default::default()
+				},
+			);
+		}
+		sources
+	}
+
+	/// The access log settings, `None` when requests are not logged.
+	pub fn access_log(&self) -> Option<AccessLog> {
+		let log = match self.access_log.clone()? {
+			AccessLogSetting::Format(format) => AccessLog {
+				format,
+				..Default::default()
+			},
+			AccessLogSetting::Custom(log) => log,
+		};
+		(log.format != AccessLogFormat::Off).then_some(log)
+	}
+
+	/// Checks the values serde cannot, so a bad file is rejected before it is used.
+	pub fn validate(&self) -> Result<()> {
+		let listeners = self.listeners()?;
+		for (address, servers) in &listeners {
+			let tls = servers
+				.iter()
+				.filter(|&&index| self.http.servers[index].tls.is_some())
+				.count();
+			if tls != 0 && tls != servers.len() {
+				return Err(Error::Generic(f!(
+					"listen address {address} mixes server blocks with and without tls"
+				)));
+			}
+		}
+		for address in self.redirect_listeners()?.keys() {
+			if listeners.contains_key(address) {
+				return Err(Error::Generic(f!(
+					"redirect_listen address {address} is also a listen address"
+				)));
+			}
+		}
+
+		if !self.ip_check_interval.is_empty()
+			&& !matches!(self.ip_check_interval.parse::<u64>(), Ok(secs) if secs > 0)
+		{
+			return Err(Error::Generic(f!(
+				"ip_check_interval {:?} is not a number of seconds",
+				self.ip_check_interval
+			)));
+		}
+		self.default_ip_list()?;
+		self.trusted_proxy_list()?;
+		if let Some(access) = &self.access {
+			access.validate(&self.ip_lists)?;
+		}
+		for (name, source) in &self.ip_lists {
+			let valid_name = !name.is_empty()
+				&& name != WHITELIST_LIST
+				&& name != ADMIN_LIST
+				&& name
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+			if !valid_name {
+				return Err(Error::Generic(f!(
+					"ip_lists: {name:?} must be made of letters, digits, '-' and '_' and differ \
+					 from {WHITELIST_LIST:?} and {ADMIN_LIST:?}"
+				)));
+			}
+			let valid_url = source.url.as_ref().is_none_or(|url| {
+				url.parse::<Uri>()
+					.is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")))
+			});
+			if source.url.is_some() == source.file.is_some() || !valid_url {
+				return Err(Error::Generic(f!(
+					"ip_lists: {name:?} needs either an http(s) url or a file"
+				)));
+			}
+		}
+
+		crate::logging::parse_filter(&self.log_level)
+			.map_err(|e| Error::Generic(f!("log_level: {}", e)))?;
+
+		if let Some(log) = self.access_log() {
+			if log
+				.file
+				.as_deref()
+				.is_some_and(|file| file.trim().is_empty())
+			{
+				return Err(Error::Generic("access_log: file must not be empty".into()));
+			}
+			let rotates = log.max_size.is_some() || log.rotate.is_some();
+			if rotates && (log.file.is_none() || log.max_files == 0) {
+				return Err(Error::Generic(
+					"access_log: rotation needs a file and max_files of at least 1".into(),
+				));
+			}
+		}
+
+		if let Some(admin) = &self.admin {
+			admin.address()?;
+			if admin.token.trim().is_empty() {
+				return Err(Error::Generic("admin: token must not be empty".into()));
+			}
+		}
+		if let Some(tracing) = &self.tracing {
+			tracing.validate()?;
+		}
+		if let Some(path) = &self.metrics_path {
+			if !path.starts_with('/') {
+				return Err(Error::Generic(f!(
+					"metrics_path {:?} must start with '/'",
+					path
+				)));
+			}
+		}
+
+		for server in &self.http.servers {
+			let client_auth = server.tls.as_ref().and_then(|tls| tls.client_auth.as_ref());
+			for header in client_auth.iter().flat_map(|auth| auth.headers()).flatten() {
+				if header.parse::<HeaderName>().is_err() {
+					return Err(Error::Generic(f!(
+						"server {:?}: invalid client_auth header name {:?}",
+						server.name,
+						header
+					)));
+				}
+			}
+			if let Some(access) = &server.access {
+				access
+					.validate(&self.ip_lists)
+					.map_err(|e| Error::Generic(f!("server {:?}: {}", server.name, e)))?;
+			}
+			for proxy in &server.proxies {
+				if let Some(access) = &proxy.access {
+					access.validate(&self.ip_lists).map_err(|e| {
+						Error::Generic(f!(
+							"server {:?}: proxy {:?}: {}",
+							server.name,
+							proxy.proxy_path,
+							e
+						))
+					})?;
+				}
+				if let Some(tls) = &proxy.upstream_tls {
+					if tls.cert.is_some() != tls.key.is_some() {
+						return Err(Error::Generic(f!(
+							"server {:?}: upstream_tls of {:?} needs both cert and key",
+							server.name,
+							proxy.proxy_path
+						)));
+					}
+					if let Some(name) = &tls.server_name {
+						ServerName::try_from(name.as_str()).map_err(|_| {
+							Error::Generic(f!(
+								"server {:?}: invalid upstream_tls server_name {:?}",
+								server.name,
+								name
+							))
+						})?;
+					}
+				}
+				if proxy.require_client_cert && client_auth.is_none() {
+					return Err(Error::Generic(f!(
+						"server {:?}: require_client_cert of {:?} needs tls.client_auth",
+						server.name,
+						proxy.proxy_path
+					)));
+				}
+				let request_headers = proxy
+					.request_headers
+					.iter()
+					.flatten()
+					.filter_map(|header| header.headers.as_ref())
+					.flatten();
+				for (name, value) in request_headers {
+					if name.parse::<HeaderName>().is_err() || value.parse::<HeaderValue>().is_err()
+					{
+						return Err(Error::Generic(f!(
+							"server {:?}: invalid request header {:?} of {:?}",
+							server.name,
+							name,
+							proxy.proxy_path
+						)));
+					}
+				}
+				if let Some(upstream) = proxy
+					.upstreams
+					.iter()
+					.find(|upstream| upstream.weight > MAX_WEIGHT)
+				{
+					return Err(Error::Generic(f!(
+						"server {:?}: weight of upstream {:?} must be at most {MAX_WEIGHT}",
+						server.name,
+						upstream.url
+					)));
+				}
+				if proxy
+					.retry
+					.as_ref()
+					.is_some_and(|retry| retry.attempts == 0)
+				{
+					return Err(Error::Generic(f!(
+						"server {:?}: retry attempts of {:?} must be at least 1",
+						server.name,
+						proxy.proxy_path
+					)));
+				}
+				if let Some(breaker) = &proxy.circuit_breaker {
+					if breaker.window == 0
+						|| breaker.half_open_requests == 0
+						|| !(1..=100).contains(&breaker.error_rate)
+					{
+						return Err(Error::Generic(f!(
+							"server {:?}: circuit_breaker of {:?} needs a non zero window and \
+							 half_open_requests and an error_rate between 1 and 100",
+							server.name,
+							proxy.proxy_path
+						)));
+					}
+				}
+				if let Some(check) = &proxy.health_check {
+					if check.interval == 0 || check.timeout == 0 || !check.path.starts_with('/') {
+						return Err(Error::Generic(f!(
+							"server {:?}: health_check of {:?} needs a path starting with '/' and \
+							 a non zero interval and timeout",
+							server.name,
+							proxy.proxy_path
+						)));
+					}
+				}
+				if proxy.match_type != MatchType::Regex && !proxy.proxy_path.starts_with('/') {
+					return Err(Error::Generic(f!(
+						"server {:?}: proxy_path {:?} must start with '/'",
+						server.name,
+						proxy.proxy_path
+					)));
+				}
+				for url in proxy.upstream_urls() {
+					let valid_url = url
+						.parse::<Uri>()
+						.map(|uri| matches!(uri.scheme_str(), Some("http" | "https")))
+						.unwrap_or(false);
+					if !valid_url {
+						return Err(Error::Generic(f!(
+							"server {:?}: upstream {:?} of {:?} is not an http(s) URL",
+							server.name,
+							url,
+							proxy.proxy_path
+						)));
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Groups server blocks with a TLS `redirect_listen` under each of those addresses.
+	pub fn redirect_listeners(&self) -> Result<BTreeMap<SocketAddr, Vec<usize>>> {
+		let mut listeners: BTreeMap<SocketAddr, Vec<usize>> = BTreeMap::new();
+		for (index, server) in self.http.servers.iter().enumerate() {
+			let Some(listen) = server
+				.tls
+				.as_ref()
+				.and_then(|tls| tls.redirect_listen.as_ref())
+			else {
+				continue;
+			};
+			let addresses = listen.addresses().map_err(|e| {
+				Error::Generic(f!("server {:?}: redirect_listen: {}", server.name, e))
+			})?;
+			for address in addresses {
+				listeners.entry(address).or_default().push(index);
+			}
+		}
+		Ok(listeners)
+	}
+
+	/// Groups server blocks (by index into `http.servers`) under each socket address they listen on.
+	pub fn listeners(&self) -> Result<BTreeMap<SocketAddr, Vec<usize>>> {
+		let mut listeners: BTreeMap<SocketAddr, Vec<usize>> = BTreeMap::new();
+		for (index, server) in self.http.servers.iter().enumerate() {
+			let addresses = server
+				.listen
+				.addresses()
+				.map_err(|e| Error::Generic(f!("server {:?}: {}", server.name, e)))?;
+			for address in addresses {
+				listeners.entry(address).or_default().push(index);
+			}
+		}
+		Ok(listeners)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_listen_addresses() {
+		let listen = Listen::One("3400".into());
+		assert_eq!(
+			listen.addresses().unwrap(),
+			vec!["0.0.0.0:3400".parse().unwrap()]
+		);
+
+		let listen = Listen::One("127.0.0.1:3400, [::]:3401, *:3402".into());
+		let expected: Vec<SocketAddr> = vec![
+			"127.0.0.1:3400".parse().unwrap(),
+			"[::]:3401".parse().unwrap(),
+			"0.0.0.0:3402".parse().unwrap(),
+		];
+		assert_eq!(listen.addresses().unwrap(), expected);
+
+		let listen = Listen::Many(vec!["3400".into(), "0.0.0.0:3400".into()]);
+		assert_eq!(listen.addresses().unwrap().len(), 1);
+
+		assert!(Listen::One("not a port".into()).addresses().is_err());
+		assert!(Listen::One("*:http".into()).addresses().is_err());
+		assert!(Listen::One(" , ".into()).addresses().is_err());
+		assert!(Listen::Many(Vec::new()).addresses().is_err());
+	}
+
+	#[test]
+	fn validates_upstream_tls() {
+		let config = |upstream_tls: &str| -> Configuration {
+			serde_json::from_str(&format!(
+				r#"{{"http": {{"servers": [{{"root": "static", "name": "a", "listen": "3400",
+				"proxies": [{{"proxy_pass": "https://10.0.0.1", "proxy_path": "/",
+				"retain_path": true, "upstream_tls": {upstream_tls}}}]}}]}}}}"#
+			))
+			.unwrap()
+		};
+		assert!(
+			config(r#"{"server_name": "internal.test", "min_version": "1.3"}"#)
+				.validate()
+				.is_ok()
+		);
+		assert!(config(r#"{"cert": "client.crt"}"#).validate().is_err());
+		assert!(config(r#"{"server_name": "not a name"}"#)
+			.validate()
+			.is_err());
+	}
+
+	#[test]
+	fn validates_request_headers() {
+		let config = |header: &str| -> Configuration {
+			serde_json::from_str(&format!(
+				r#"{{"http": {{"servers": [{{"root": "static", "name": "a", "listen": "3400",
+				"proxies": [{{"proxy_pass": "http://10.0.0.1", "proxy_path": "/",
+				"retain_path": true, "request_headers": [{header}]}}]}}]}}}}"#
+			))
+			.unwrap()
+		};
+		assert!(config(r#"{"X-Tenant": "$tenant"}"#).validate().is_ok());
+		assert!(config(r#"{"X Tenant": "a"}"#).validate().is_err());
+		assert!(config(r#"{"X-Tenant": "a\nb"}"#).validate().is_err());
+	}
+
+	#[test]
+	fn limits_upstream_weights() {
+		let config = |weight: u32| -> Configuration {
+			serde_json::from_str(&format!(
+				r#"{{"http": {{"servers": [{{"root": "static", "name": "a", "listen": "3400",
+				"proxies": [{{"proxy_pass": "", "proxy_path": "/", "retain_path": true,
+				"upstreams": [{{"url": "http://10.0.0.1", "weight": {weight}}}]}}]}}]}}}}"#
+			))
+			.unwrap()
+		};
+		assert!(config(MAX_WEIGHT).validate().is_ok());
+		assert!(config(MAX_WEIGHT + 1).validate().is_err());
+		assert!(config(u32::MAX).validate().is_err());
+	}
+
+	#[test]
+	fn supports_otlp_over_http_json_only() {
+		let tracing = |protocol: &str| -> Tracing {
+			serde_json::from_str(&format!(
+				r#"{{"endpoint": "http://127.0.0.1:4318/v1/traces"{protocol}}}"#
+			))
+			.unwrap()
+		};
+		assert_eq!(tracing("").protocol, OtlpProtocol::HttpJson);
+		assert!(tracing("").validate().is_ok());
+		assert!(tracing(r#", "protocol": "http/json""#).validate().is_ok());
+		assert!(tracing(r#", "protocol": "grpc""#).validate().is_err());
+		assert!(tracing(r#", "protocol": "http/protobuf""#)
+			.validate()
+			.is_err());
+	}
+
+	#[test]
+	fn validates_circuit_breaker_error_rate() {
+		let config = |error_rate: u32| -> Configuration {
+			serde_json::from_str(&format!(
+				r#"{{"http": {{"servers": [{{"root": "static", "name": "a", "listen": "3400",
+				"proxies": [{{"proxy_pass": "http://10.0.0.1", "proxy_path": "/",
+				"retain_path": true, "circuit_breaker": {{"error_rate": {error_rate}}}}}]}}]}}}}"#
+			))
+			.unwrap()
+		};
+		assert!(config(1).validate().is_ok());
+		assert!(config(100).validate().is_ok());
+		assert!(config(0).validate().is_err());
+		assert!(config(101).validate().is_err());
+	}
+}
@@ -6,9 +6,10 @@ pub enum Error {
 	#[error("Generic error: {0}")]
 	Generic(String),
 	/// For starter, to remove as code matures.
+	#[allow(dead_code)]
 	#[error("Static error: {0}")]
 	Static(&'static str),
 
 	#[error(transparent)]
 	IO(#[from] std::io::Error),
-}
\ No newline at end of file
+}
@@ -0,0 +1,233 @@
+use std::{
+	env,
+	io::Write,
+	sync::{Mutex, OnceLock, RwLock},
+};
+
+use env_logger::{
+	filter::{Builder as FilterBuilder, Filter},
+	fmt::TimestampPrecision,
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use regex::Regex;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::{Configuration, LogFormat};
+
+// Level used when neither `log_level` nor RUST_LOG are set
+const DEFAULT_LEVEL: &str = "info";
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Log settings, from the configuration or changed through the admin API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Settings {
+	level: String,
+	format: LogFormat,
+	timestamps: bool,
+}
+
+impl Settings {
+	fn from_config(config: &Configuration) -> Self {
+		Settings {
+			level: effective_level(&config.log_level),
+			format: config.log_format,
+			timestamps: config.log_timestamps,
+		}
+	}
+}
+
+// An empty `log_level` falls back to RUST_LOG, then to the default level
+fn effective_level(level: &str) -> String {
+	match level.trim() {
+		"" => env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LEVEL.to_string()),
+		level => level.to_string(),
+	}
+}
+
+/// Process logger whose filter and format can change at runtime, unlike env_logger's.
+struct Logger {
+	inner: RwLock<Inner>,
+	// settings last taken from the configuration
+	configured: Mutex<Option<Settings>>,
+}
+
+struct Inner {
+	settings: Settings,
+	filter: Filter,
+	// formats and writes the records that pass `filter`
+	output: env_logger::Logger,
+}
+
+impl Inner {
+	fn new(settings: Settings) -> Self {
+		let filter = FilterBuilder::new().parse(&settings.level).build();
+		let mut output = env_logger::Builder::new();
+		output.filter_level(LevelFilter::Trace);
+		if let Ok(style) = env::var("RUST_LOG_STYLE") {
+			output.parse_write_style(&style);
+		}
+		match settings.format {
+			LogFormat::Text => {
+				output.format_timestamp(settings.timestamps.then_some(TimestampPrecision::Millis));
+			}
+			LogFormat::Json => {
+				output.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+			}
+		}
+		Inner {
+			settings,
+			filter,
+			output: output.build(),
+		}
+	}
+}
+
+impl Log for Logger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.inner.read().unwrap().filter.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		let inner = self.inner.read().unwrap();
+		if inner.filter.matches(record) {
+			inner.output.log(record);
+		}
+	}
+
+	fn flush(&self) {
+		self.inner.read().unwrap().output.flush();
+	}
+}
+
+impl Logger {
+	fn apply(&self, settings: Settings) {
+		let inner = Inner::new(settings);
+		log::set_max_level(inner.filter.filter());
+		*self.inner.write().unwrap() = inner;
+	}
+}
+
+/// Installs the process logger, following RUST_LOG until the configuration is loaded.
+pub fn init() {
+	let settings = Settings {
+		level: effective_level(""),
+		format: LogFormat::Text,
+		timestamps: false,
+	};
+	let logger = LOGGER.get_or_init(|| Logger {
+		inner: RwLock::new(Inner::new(settings)),
+		configured: Mutex::new(None),
+	});
+	if log::set_logger(logger).is_ok() {
+		log::set_max_level(logger.inner.read().unwrap().filter.filter());
+	}
+}
+
+/// Applies the log settings of a configuration. A reload leaving them unchanged keeps a level
+/// set through the admin API.
+pub fn configure(config: &Configuration) {
+	let Some(logger) = LOGGER.get() else {
+		return;
+	};
+	let settings = Settings::from_config(config);
+	let mut configured = logger.configured.lock().unwrap();
+	if configured.as_ref() == Some(&settings) {
+		return;
+	}
+	*configured = Some(settings.clone());
+	drop(configured);
+	let level = settings.level.clone();
+	logger.apply(settings);
+	log::info!("log level set to {:?}", level);
+}
+
+/// The filter in use.
+pub fn level() -> String {
+	LOGGER
+		.get()
+		.map(|logger| logger.inner.read().unwrap().settings.level.clone())
+		.unwrap_or_default()
+}
+
+/// Changes the filter until the configuration changes it again.
+pub fn set_level(level: &str) -> Result<(), String> {
+	parse_filter(level)?;
+	let logger = LOGGER.get().ok_or("no logger installed")?;
+	let mut settings = logger.inner.read().unwrap().settings.clone();
+	settings.level = effective_level(level);
+	logger.apply(settings);
+	Ok(())
+}
+
+/// Checks an env_logger filter such as `warn,rust_reverse_proxy::usecase::proxy=debug/regex`.
+/// env_logger itself ignores the directives it cannot read.
+pub fn parse_filter(spec: &str) -> Result<(), String> {
+	let (directives, regex) = match spec.split_once('/') {
+		Some((directives, regex)) => (directives, Some(regex)),
+		None => (spec, None),
+	};
+	let is_module = |module: &str| {
+		!module.is_empty()
+			&& module
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+	};
+	for directive in directives.split(',').map(str::trim) {
+		let valid = match directive.split_once('=') {
+			Some((module, level)) => {
+				is_module(module.trim()) && level.trim().parse::<LevelFilter>().is_ok()
+			}
+			None => directive.is_empty() || is_module(directive),
+		};
+		if !valid {
+			return Err(format!("invalid directive {:?}", directive));
+		}
+	}
+	if let Some(regex) = regex {
+		Regex::new(regex).map_err(|e| format!("invalid message filter: {}", e))?;
+	}
+	Ok(())
+}
+
+fn json_line(record: &Record) -> String {
+	serde_json::json!({
+		"time": OffsetDateTime::now_utc().format(&Rfc3339).ok(),
+		"level": record.level().as_str(),
+		"target": record.target(),
+		"message": record.args().to_string(),
+	})
+	.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn checks_filters() {
+		assert!(parse_filter("").is_ok());
+		assert!(parse_filter("debug").is_ok());
+		assert!(parse_filter("warn, rust_reverse_proxy::usecase::proxy=debug,hyper=off").is_ok());
+		assert!(parse_filter("info/upstream \\d+").is_ok());
+		assert!(parse_filter("rust_reverse_proxy=loud").is_err());
+		assert!(parse_filter("info debug").is_err());
+		assert!(parse_filter("info/(").is_err());
+	}
+
+	#[test]
+	fn writes_json_lines() {
+		let line = json_line(
+			&Record::builder()
+				.level(log::Level::Warn)
+				.target("rust_reverse_proxy::reload")
+				.args(format_args!("rejected \"x\""))
+				.build(),
+		);
+		let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+		assert_eq!(value["level"], "WARN");
+		assert_eq!(value["target"], "rust_reverse_proxy::reload");
+		assert_eq!(value["message"], "rejected \"x\"");
+		assert!(value["time"].is_string());
+	}
+}
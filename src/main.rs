@@ -1,24 +1,28 @@
+mod access_log;
+mod admin;
 mod config;
+mod error;
+mod logging;
+mod metrics;
+mod prelude;
+mod reload;
+mod state;
+mod tls;
+mod trace;
 mod usecase;
 mod utils;
-use config::Configuration;
+use config::ConfigSource;
+use state::{AppState, Snapshot};
+use usecase::proxy::Connection;
 
 use dotenv::dotenv;
-use env_logger::Env;
 
 mod schedule_task;
-use std::{
-	collections::HashSet,
-	env,
-	fs::File,
-	io::Read,
-	net::{IpAddr, SocketAddr},
-	path::Path,
-	sync::{Arc, Mutex},
-};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::task::{self};
 
 use hyper::{
+	server::conn::AddrStream,
 	service::{make_service_fn, service_fn},
 	Server,
 };
@@ -31,68 +35,100 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[tokio::main]
 async fn main() {
 	dotenv().ok();
-	// init logger with checking inside env_var wether timestamp is true or false
-	env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-		.format_timestamp(None)
-		.init();
-	// Get the CONFIG_SETTING environment variable
-	let config_setting = env::var("CONFIG_SETTING");
-
-	let config: Configuration;
-
-	match config_setting {
-		Ok(val) => {
-			// If CONFIG_SETTING is set, parse it
-			log::info!("Using config from environment variable");
-			config = serde_json::from_str(&val).expect("JSON was not well-formatted");
-		}
-		Err(_) => {
-			// If CONFIG_SETTING is not set, parse the file
-			log::info!("Using config from config.json");
-			let json_file_path = Path::new("config.json");
-			let mut json_file = File::open(&json_file_path).expect("File open failed");
-			let mut json_content = String::new();
-			json_file
-				.read_to_string(&mut json_content)
-				.expect("File read failed");
-			config = serde_json::from_str(&json_content).expect("JSON was not well-formatted");
-		}
+	// RUST_LOG applies until the configuration sets log_level
+	logging::init();
+	// Read the CONFIG_SETTING environment variable, or config.json when it is not set
+	let source = ConfigSource::from_env();
+	match &source {
+		ConfigSource::Env => log::info!("Using config from environment variable"),
+		ConfigSource::File(path) => log::info!("Using config from {}", path.display()),
 	}
 
-	let mut server_tasks = Vec::new();
+	let snapshot = match source.load().and_then(|config| Snapshot::new(config, None)) {
+		Ok(snapshot) => snapshot,
+		Err(e) => {
+			log::error!("Invalid configuration: {}", e);
+			std::process::exit(1);
+		}
+	};
+	logging::configure(&snapshot.config);
+	let listeners: Vec<SocketAddr> = snapshot.listeners.keys().copied().collect();
+	let tls_listeners: Vec<SocketAddr> = listeners
+		.iter()
+		.copied()
+		.filter(|addr| snapshot.is_tls(addr))
+		.collect();
+	let redirect_listeners: Vec<SocketAddr> = snapshot.redirects.keys().copied().collect();
+	// validated with the configuration
+	let admin_addr = snapshot
+		.config
+		.admin
+		.as_ref()
+		.and_then(|admin| admin.address().ok());
+	let state = Arc::new(AppState::new(
+		snapshot,
+		access_log::AccessLogger::start(),
+		trace::Tracer::start(),
+	));
 
-	let whitelisted_ips: Arc<Mutex<HashSet<IpAddr>>> = Arc::new(Mutex::new(HashSet::new()));
+	let mut server_tasks = Vec::new();
 	let client = reqwest::Client::new();
 
-	for server in &config.http.servers {
-		let config_port = server.listen.clone();
-		let config = Arc::new(config.clone());
-		let whitelisted_ips = Arc::clone(&whitelisted_ips);
-		let server_task = task::spawn(async move {
+	for &addr in &tls_listeners {
+		server_tasks.push(task::spawn(tls::serve(addr, Arc::clone(&state))));
+	}
+
+	for addr in redirect_listeners.iter().copied() {
+		let state = Arc::clone(&state);
+		server_tasks.push(task::spawn(async move {
 			let make_svc = make_service_fn(move |_| {
-				let config = Arc::new(config.clone());
-				let whitelisted_ips = Arc::clone(&whitelisted_ips);
+				let state = Arc::clone(&state);
 				async move {
 					Ok::<_, hyper::Error>(service_fn(move |req| {
-						usecase::proxy::mirror(
-							req,
-							Arc::clone(&whitelisted_ips),
-							Arc::clone(&config),
-						)
+						usecase::redirect::to_https(req, Arc::clone(&state), addr)
 					}))
 				}
 			});
+			match Server::try_bind(&addr) {
+				Ok(builder) => {
+					log::info!("redirecting to https on {}", addr);
+					if let Err(e) = builder.serve(make_svc).await {
+						log::error!("redirect server error: {}", e);
+					}
+				}
+				Err(e) => log::error!("failed to bind {}: {}", addr, e),
+			}
+		}));
+	}
 
-			// get port from env to int or get from env
-			let port = config_port.parse::<u16>().unwrap_or_else(|_| {
-				env::var("PORT")
-					.unwrap_or_else(|_| "8080".to_string())
-					.parse::<u16>()
-					.expect("PORT must be a number")
+	for addr in listeners.iter().copied() {
+		if tls_listeners.contains(&addr) {
+			continue;
+		}
+		let state = Arc::clone(&state);
+		let server_task = task::spawn(async move {
+			let make_svc = make_service_fn(move |conn: &AddrStream| {
+				let state = Arc::clone(&state);
+				let conn = Arc::new(Connection {
+					listener: addr,
+					remote: conn.remote_addr(),
+					tls: None,
+				});
+				async move {
+					Ok::<_, hyper::Error>(service_fn(move |req| {
+						usecase::proxy::mirror(req, Arc::clone(&state), Arc::clone(&conn))
+					}))
+				}
 			});
 
-			let addr = SocketAddr::from(([0, 0, 0, 0], port));
-			let server = Server::bind(&addr).serve(make_svc);
+			let server = match Server::try_bind(&addr) {
+				Ok(builder) => builder.serve(make_svc),
+				Err(e) => {
+					log::error!("failed to bind {}: {}", addr, e);
+					return;
+				}
+			};
+			log::info!("listening on {}", addr);
 
 			if let Err(e) = server.await {
 				log::error!("server error: {}", e);
@@ -101,17 +137,25 @@ async fn main() {
 		server_tasks.push(server_task);
 	}
 
-	// if whitelist url is not empty
-	if !config.ip_whitelist_url.is_empty() {
-		let whitelist_updater_task = schedule_task::create_whitelist_updater_task(
-			client.clone(),
-			Arc::new(config.clone()),
-			Arc::clone(&whitelisted_ips),
-		)
-		.await;
+	let whitelist_updater_task =
+		schedule_task::create_whitelist_updater_task(client.clone(), Arc::clone(&state)).await;
+	server_tasks.push(whitelist_updater_task);
+
+	server_tasks.push(tls::create_cert_watch_task(Arc::clone(&state)));
+
+	server_tasks.push(access_log::create_reopen_task(Arc::clone(&state)));
 
-		server_tasks.push(whitelist_updater_task);
+	let health_check_task = schedule_task::create_health_check_task(Arc::clone(&state));
+	server_tasks.push(health_check_task);
+
+	if let Some(addr) = admin_addr {
+		server_tasks.push(admin::create_admin_task(Arc::clone(&state), addr));
 	}
+
+	let bound = listeners.into_iter().chain(redirect_listeners).collect();
+	let reload_task = reload::create_config_reload_task(Arc::clone(&state), source, bound);
+	server_tasks.push(reload_task);
+
 	let servers = futures::future::join_all(server_tasks).await;
 
 	for server in servers {
@@ -0,0 +1,570 @@
+use std::{
+	collections::BTreeMap,
+	fmt::{Display, Write as _},
+	sync::{
+		atomic::{AtomicI64, AtomicU64, Ordering},
+		Mutex,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Response};
+
+use crate::{
+	prelude::*, state::AppState, usecase::circuit_breaker::CircuitState,
+	utils::control_headers::CacheStatus,
+};
+
+/// Counters of the whole process, kept across configuration reloads.
+pub static METRICS: Metrics = Metrics::new();
+
+// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Server block and route a request was routed to, empty when none matched.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RouteLabels {
+	pub server: String,
+	// `proxy_path` of the proxy, empty for static files
+	pub route: String,
+}
+
+impl RouteLabels {
+	fn pairs(&self) -> [(&str, &str); 2] {
+		[("server", &self.server), ("route", &self.route)]
+	}
+}
+
+/// A finished request, as counted by [`Metrics::record_request`].
+#[derive(Debug)]
+pub struct Completed {
+	pub status: u16,
+	pub latency: Duration,
+	// until the upstream response headers were received
+	pub upstream_latency: Option<Duration>,
+	pub bytes_in: u64,
+	pub bytes_out: u64,
+	pub cache: Option<CacheStatus>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+	// observations per bucket, not cumulative, the last one above every bound
+	counts: [u64; BUCKETS.len() + 1],
+	sum: f64,
+}
+
+impl Histogram {
+	fn observe(&mut self, value: Duration) {
+		let seconds = value.as_secs_f64();
+		let bucket = BUCKETS
+			.iter()
+			.position(|bound| seconds <= *bound)
+			.unwrap_or(BUCKETS.len());
+		self.counts[bucket] += 1;
+		self.sum += seconds;
+	}
+}
+
+#[derive(Debug, Default)]
+struct RouteMetrics {
+	requests: [u64; STATUS_CLASSES.len()],
+	latency: Histogram,
+	upstream_latency: Histogram,
+	bytes_in: u64,
+	bytes_out: u64,
+	cache_hits: u64,
+	cache_misses: u64,
+}
+
+struct CompressionBytes {
+	coding: &'static str,
+	input: AtomicU64,
+	output: AtomicU64,
+}
+
+impl CompressionBytes {
+	const fn new(coding: &'static str) -> Self {
+		CompressionBytes {
+			coding,
+			input: AtomicU64::new(0),
+			output: AtomicU64::new(0),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Refresh {
+	succeeded: bool,
+	at: SystemTime,
+}
+
+pub struct Metrics {
+	routes: Mutex<BTreeMap<RouteLabels, RouteMetrics>>,
+	in_flight: AtomicI64,
+	compression: [CompressionBytes; 3],
+	// outcome of the last fetch of every list source, by list name
+	refreshes: Mutex<BTreeMap<String, Refresh>>,
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+pub struct InFlight(());
+
+impl InFlight {
+	pub fn start() -> Self {
+		METRICS.in_flight.fetch_add(1, Ordering::Relaxed);
+		InFlight(())
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		METRICS.in_flight.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+impl Metrics {
+	const fn new() -> Self {
+		Metrics {
+			routes: Mutex::new(BTreeMap::new()),
+			in_flight: AtomicI64::new(0),
+			compression: [
+				CompressionBytes::new("gzip"),
+				CompressionBytes::new("deflate"),
+				CompressionBytes::new("br"),
+			],
+			refreshes: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	pub fn record_request(&self, labels: &RouteLabels, completed: &Completed) {
+		let mut routes = self.routes.lock().unwrap();
+		let route = match routes.get_mut(labels) {
+			Some(route) => route,
+			None => routes.entry(labels.clone()).or_default(),
+		};
+		let class = usize::from(completed.status / 100).checked_sub(1);
+		if let Some(count) = class.and_then(|class| route.requests.get_mut(class)) {
+			*count += 1;
+		}
+		route.latency.observe(completed.latency);
+		if let Some(latency) = completed.upstream_latency {
+			route.upstream_latency.observe(latency);
+		}
+		route.bytes_in += completed.bytes_in;
+		route.bytes_out += completed.bytes_out;
+		match completed.cache {
+			Some(CacheStatus::Hit) => route.cache_hits += 1,
+			Some(CacheStatus::Miss) => route.cache_misses += 1,
+			None => {}
+		}
+	}
+
+	/// Counts bytes given to (`output` false) or produced by an encoder.
+	pub fn record_compression(&self, coding: &str, output: bool, bytes: usize) {
+		let Some(counter) = self
+			.compression
+			.iter()
+			.find(|counter| counter.coding == coding)
+		else {
+			return;
+		};
+		let counter = if output {
+			&counter.output
+		} else {
+			&counter.input
+		};
+		counter.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	pub fn record_ip_list_refresh(&self, name: &str, succeeded: bool) {
+		let refresh = Refresh {
+			succeeded,
+			at: SystemTime::now(),
+		};
+		self.refreshes
+			.lock()
+			.unwrap()
+			.insert(name.to_string(), refresh);
+	}
+
+	/// Forgets the lists that are no longer configured.
+	pub fn retain_ip_lists(&self, keep: impl Fn(&str) -> bool) {
+		self.refreshes.lock().unwrap().retain(|name, _| keep(name));
+	}
+
+	/// Every metric in the Prometheus text format, with the gauges read from `state`.
+	pub fn render(&self, state: &AppState) -> String {
+		let mut out = Exposition::default();
+		self.render_requests(&mut out);
+
+		out.family(
+			"proxy_requests_in_flight",
+			"gauge",
+			"Requests being served.",
+		);
+		out.sample(
+			"proxy_requests_in_flight",
+			&[],
+			self.in_flight.load(Ordering::Relaxed),
+		);
+
+		for (name, help, output) in [
+			(
+				"proxy_compression_input_bytes_total",
+				"Bytes given to the response encoders, by content coding.",
+				false,
+			),
+			(
+				"proxy_compression_output_bytes_total",
+				"Bytes produced by the response encoders, by content coding.",
+				true,
+			),
+		] {
+			out.family(name, "counter", help);
+			for counter in &self.compression {
+				let bytes = if output {
+					&counter.output
+				} else {
+					&counter.input
+				};
+				out.sample(
+					name,
+					&[("encoding", counter.coding)],
+					bytes.load(Ordering::Relaxed),
+				);
+			}
+		}
+
+		render_ip_lists(&mut out, state, &self.refreshes.lock().unwrap());
+		render_upstreams(&mut out, state);
+		out.0
+	}
+
+	fn render_requests(&self, out: &mut Exposition) {
+		let routes = self.routes.lock().unwrap();
+
+		out.family(
+			"proxy_requests_total",
+			"counter",
+			"Requests served, by server block, route and status class.",
+		);
+		for (route, metrics) in routes.iter() {
+			for (class, count) in STATUS_CLASSES.iter().zip(metrics.requests) {
+				if count > 0 {
+					let labels = [&route.pairs()[..], &[("status", class)]].concat();
+					out.sample("proxy_requests_total", &labels, count);
+				}
+			}
+		}
+
+		for (name, help, upstream) in [
+			(
+				"proxy_request_duration_seconds",
+				"Time until the response body was sent.",
+				false,
+			),
+			(
+				"proxy_upstream_duration_seconds",
+				"Time until the upstream response headers were received.",
+				true,
+			),
+		] {
+			out.family(name, "histogram", help);
+			for (route, metrics) in routes.iter() {
+				let histogram = if upstream {
+					&metrics.upstream_latency
+				} else {
+					&metrics.latency
+				};
+				out.histogram(name, &route.pairs(), histogram);
+			}
+		}
+
+		for (name, help, output) in [
+			(
+				"proxy_request_bytes_total",
+				"Request body bytes received from clients.",
+				false,
+			),
+			(
+				"proxy_response_bytes_total",
+				"Response body bytes sent to clients.",
+				true,
+			),
+		] {
+			out.family(name, "counter", help);
+			for (route, metrics) in routes.iter() {
+				let bytes = if output {
+					metrics.bytes_out
+				} else {
+					metrics.bytes_in
+				};
+				out.sample(name, &route.pairs(), bytes);
+			}
+		}
+
+		for (name, help, hits) in [
+			(
+				"proxy_cache_hits_total",
+				"Responses answered with 304 Not Modified from the client's cache.",
+				true,
+			),
+			(
+				"proxy_cache_misses_total",
+				"Cacheable responses sent in full.",
+				false,
+			),
+		] {
+			out.family(name, "counter", help);
+			for (route, metrics) in routes.iter() {
+				let count = if hits {
+					metrics.cache_hits
+				} else {
+					metrics.cache_misses
+				};
+				out.sample(name, &route.pairs(), count);
+			}
+		}
+	}
+}
+
+fn render_ip_lists(out: &mut Exposition, state: &AppState, refreshes: &BTreeMap<String, Refresh>) {
+	out.family(
+		"proxy_ip_list_entries",
+		"gauge",
+		"Allowed and denied ranges of every loaded ip list.",
+	);
+	let ip_lists = state.ip_lists.lock().unwrap();
+	let mut names: Vec<&String> = ip_lists.keys().collect();
+	names.sort();
+	for name in names {
+		let list = &ip_lists[name];
+		out.sample(
+			"proxy_ip_list_entries",
+			&[("list", name), ("kind", "allow")],
+			list.allow.len(),
+		);
+		out.sample(
+			"proxy_ip_list_entries",
+			&[("list", name), ("kind", "deny")],
+			list.deny.len(),
+		);
+	}
+	drop(ip_lists);
+
+	out.family(
+		"proxy_ip_list_last_refresh_success",
+		"gauge",
+		"Whether the last fetch of the list source succeeded.",
+	);
+	for (name, refresh) in refreshes {
+		out.sample(
+			"proxy_ip_list_last_refresh_success",
+			&[("list", name)],
+			u8::from(refresh.succeeded),
+		);
+	}
+	out.family(
+		"proxy_ip_list_last_refresh_timestamp_seconds",
+		"gauge",
+		"When the list source was last fetched.",
+	);
+	for (name, refresh) in refreshes {
+		let seconds = refresh
+			.at
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		out.sample(
+			"proxy_ip_list_last_refresh_timestamp_seconds",
+			&[("list", name)],
+			seconds,
+		);
+	}
+}
+
+fn render_upstreams(out: &mut Exposition, state: &AppState) {
+	let snapshot = state.snapshot();
+	let mut upstreams: Vec<_> = snapshot.upstreams.values().collect();
+	upstreams.sort_by(|a, b| a.url.cmp(&b.url));
+
+	out.family(
+		"proxy_upstream_up",
+		"gauge",
+		"Whether the upstream passes its health checks and is not ejected.",
+	);
+	for upstream in &upstreams {
+		let up = upstream.health.report().available;
+		out.sample(
+			"proxy_upstream_up",
+			&[("upstream", &upstream.url)],
+			u8::from(up),
+		);
+	}
+	out.family(
+		"proxy_upstream_drained",
+		"gauge",
+		"Whether the upstream was drained through the admin API.",
+	);
+	for upstream in &upstreams {
+		out.sample(
+			"proxy_upstream_drained",
+			&[("upstream", &upstream.url)],
+			u8::from(upstream.is_drained()),
+		);
+	}
+	out.family(
+		"proxy_upstream_active_requests",
+		"gauge",
+		"Requests being forwarded to the upstream.",
+	);
+	for upstream in &upstreams {
+		out.sample(
+			"proxy_upstream_active_requests",
+			&[("upstream", &upstream.url)],
+			upstream.active(),
+		);
+	}
+	out.family(
+		"proxy_upstream_circuit_state",
+		"gauge",
+		"State of the circuit breaker of the upstream.",
+	);
+	for upstream in &upstreams {
+		let current = upstream.circuit.state();
+		for (state, name) in [
+			(CircuitState::Closed, "closed"),
+			(CircuitState::Open, "open"),
+			(CircuitState::HalfOpen, "half_open"),
+		] {
+			out.sample(
+				"proxy_upstream_circuit_state",
+				&[("upstream", &upstream.url), ("state", name)],
+				u8::from(current == state),
+			);
+		}
+	}
+}
+
+/// Serves the metrics to a scraper.
+pub fn response(state: &AppState) -> Response<Body> {
+	Response::builder()
+		.header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+		.body(METRICS.render(state).into())
+		.unwrap()
+}
+
+// Prometheus text exposition format
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+	fn family(&mut self, name: &str, kind: &str, help: &str) {
+		let _ = writeln!(self.0, "# HELP {} {}", name, help);
+		let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+	}
+
+	fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+		self.0.push_str(name);
+		if !labels.is_empty() {
+			self.0.push('{');
+			for (position, (label, value)) in labels.iter().enumerate() {
+				if position > 0 {
+					self.0.push(',');
+				}
+				let _ = write!(self.0, "{}=\"{}\"", label, escape(value));
+			}
+			self.0.push('}');
+		}
+		let _ = writeln!(self.0, " {}", value);
+	}
+
+	fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+		let bucket = f!("{}_bucket", name);
+		let mut cumulative = 0;
+		for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+			cumulative += count;
+			let bound = bound.to_string();
+			let labels = [labels, &[("le", bound.as_str())]].concat();
+			self.sample(&bucket, &labels, cumulative);
+		}
+		let count: u64 = histogram.counts.iter().sum();
+		let labels_inf = [labels, &[("le", "+Inf")]].concat();
+		self.sample(&bucket, &labels_inf, count);
+		self.sample(&f!("{}_sum", name), labels, histogram.sum);
+		self.sample(&f!("{}_count", name), labels, count);
+	}
+}
+
+// Backslashes, quotes and new lines are escaped in label values
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn renders_cumulative_histograms() {
+		let mut histogram = Histogram::default();
+		for millis in [3, 40, 40, 60_000] {
+			histogram.observe(Duration::from_millis(millis));
+		}
+		let mut out = Exposition::default();
+		out.histogram("latency", &[("route", "/a\"b")], &histogram);
+		let lines: Vec<&str> = out.0.lines().collect();
+		assert_eq!(lines[0], r#"latency_bucket{route="/a\"b",le="0.005"} 1"#);
+		assert_eq!(lines[3], r#"latency_bucket{route="/a\"b",le="0.05"} 3"#);
+		assert_eq!(lines[11], r#"latency_bucket{route="/a\"b",le="30"} 3"#);
+		assert_eq!(lines[12], r#"latency_bucket{route="/a\"b",le="+Inf"} 4"#);
+		assert!(lines[13].starts_with(r#"latency_sum{route="/a\"b"} 60.08"#));
+		assert_eq!(lines[14], r#"latency_count{route="/a\"b"} 4"#);
+	}
+
+	#[test]
+	fn counts_requests_by_status_class() {
+		let metrics = Metrics::new();
+		let labels = RouteLabels {
+			server: "site".into(),
+			route: "/api".into(),
+		};
+		for (status, cache) in [
+			(200, Some(CacheStatus::Miss)),
+			(204, None),
+			(304, Some(CacheStatus::Hit)),
+			(503, None),
+			(999, None),
+			(42, None),
+		] {
+			metrics.record_request(
+				&labels,
+				&Completed {
+					status,
+					latency: Duration::from_millis(1),
+					upstream_latency: None,
+					bytes_in: 10,
+					bytes_out: 100,
+					cache,
+				},
+			);
+		}
+		let routes = metrics.routes.lock().unwrap();
+		let route = &routes[&labels];
+		assert_eq!(route.requests, [0, 2, 1, 0, 1]);
+		assert_eq!((route.cache_hits, route.cache_misses), (1, 1));
+		assert_eq!(route.bytes_out, 600);
+		assert_eq!(route.upstream_latency.counts.iter().sum::<u64>(), 0);
+	}
+}
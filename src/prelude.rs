@@ -8,7 +8,8 @@ pub type Result<T> = core::result::Result<T, Error>;
 
 // Generic Wrapper tuple struct for newtype pattern,
 // mostly for external type to type From/TryFrom conversions
+#[allow(dead_code)]
 pub struct W<T>(pub T);
 
 // Personal preference.
-pub use std::format as f;
\ No newline at end of file
+pub use std::format as f;
@@ -1,62 +1,221 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::Uri;
 use reqwest::Client;
-use std::collections::HashSet;
-use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::interval;
+use tokio::time::{interval, Instant};
 
-use crate::config;
+use crate::config::{HealthCheck, IpListSource, ADMIN_LIST};
+use crate::metrics::METRICS;
+use crate::state::AppState;
+use crate::usecase::ip_source::{self, Fetched, SourceState};
+use crate::usecase::{balancer::UpstreamState, client::HttpsClient};
 
+// How often upstreams are checked for a due health probe
+const HEALTH_CHECK_TICK: Duration = Duration::from_secs(1);
+
+// How often list sources are checked for a due refresh
+const IP_LIST_TICK: Duration = Duration::from_secs(1);
+
+// ip_check_interval is validated with the configuration, an empty value means the default (30)
+fn check_interval(ip_check_interval: &str) -> u64 {
+	ip_check_interval.parse::<u64>().unwrap_or(30)
+}
+
+/// Refreshes every IP list source every `ip_check_interval`. Sources are fetched concurrently so a
+/// slow one holds up neither the others nor the expiry of admin entries. A failing source or a
+/// list that does not pass the checks keeps the previous version of the list.
 pub async fn create_whitelist_updater_task(
 	client: Client,
-	config: Arc<config::Configuration>,
-	whitelisted_ips: Arc<Mutex<HashSet<IpAddr>>>,
+	state: Arc<AppState>,
 ) -> tokio::task::JoinHandle<()> {
-	let parsed_timeout = config.ip_check_interval.parse::<u64>();
-	let timeout = match parsed_timeout {
-		Ok(val) => val,
-		Err(_) => {
-			log::error!("Failed to parse ip_check_interval, using default value (30)");
-			30
+	tokio::task::spawn(async move {
+		let mut interval = interval(IP_LIST_TICK);
+		// refresh state by list name, with the source it was built for, `None` while it is fetched
+		let mut sources: HashMap<String, (IpListSource, Option<SourceState>)> = HashMap::new();
+		let mut fetches = FuturesUnordered::new();
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = state.refresh_ip_lists.notified() => {
+					log::info!("refreshing every ip list");
+					for source_state in sources.values_mut().filter_map(|(_, slot)| slot.as_mut()) {
+						source_state.make_due();
+					}
+				}
+				Some((name, source, source_state, result)) = fetches.next() => {
+					// a reload may have changed or removed the source in the meantime
+					if let Some((current, slot @ None)) = sources.get_mut::<String>(&name) {
+						if *current == source {
+							*slot = Some(finish_fetch(&state, &name, source_state, result).await);
+						}
+					}
+					continue;
+				}
+			}
+			state.expire_admin_entries();
+
+			// settings are read on every tick so a configuration reload is picked up
+			let snapshot = state.snapshot();
+			let config = &snapshot.config;
+			let configured = config.ip_list_sources();
+			sources.retain(|name, (source, _)| configured.get(name) == Some(source));
+			state
+				.ip_lists
+				.lock()
+				.unwrap()
+				.retain(|name, _| name == ADMIN_LIST || configured.contains_key(name));
+			METRICS.retain_ip_lists(|name| configured.contains_key(name));
+
+			for (name, source) in configured {
+				if let Entry::Vacant(entry) = sources.entry(name) {
+					let dir = config.ip_list_cache.as_deref();
+					load_cached(&state, dir, entry.key(), &source).await;
+					entry.insert((source, Some(SourceState::default())));
+				}
+			}
+
+			let now = Instant::now();
+			for (name, (source, slot)) in &mut sources {
+				let Some(mut source_state) = slot.take_if(|due| due.is_due(now)) else {
+					continue;
+				};
+				let (client, name, source) = (client.clone(), name.clone(), source.clone());
+				fetches.push(async move {
+					let result = source_state.fetch(&client, &source).await;
+					(name, source, source_state, result)
+				});
+			}
 		}
+	})
+}
+
+// Applies the outcome of a fetch and plans the next one
+async fn finish_fetch(
+	state: &AppState,
+	name: &str,
+	mut source_state: SourceState,
+	result: Result<Option<Fetched>, String>,
+) -> SourceState {
+	let snapshot = state.snapshot();
+	let config = &snapshot.config;
+	let every = Duration::from_secs(check_interval(&config.ip_check_interval));
+	let delay = source_state.schedule(result.is_ok(), every);
+	METRICS.record_ip_list_refresh(name, result.is_ok());
+	match result {
+		Ok(Some(Fetched { list, body })) => {
+			log::info!(
+				"updated ip list {}: {} allowed, {} denied",
+				name,
+				list.allow.len(),
+				list.deny.len()
+			);
+			state
+				.ip_lists
+				.lock()
+				.unwrap()
+				.insert(name.to_string(), list);
+			if let Some(dir) = &config.ip_list_cache {
+				ip_source::persist(dir, name, &body).await;
+			}
+		}
+		Ok(None) => log::debug!("ip list {} is unchanged", name),
+		Err(e) => log::warn!(
+			"failed to update ip list {}, keeping the previous one: {}; retrying in {:?}",
+			name,
+			e,
+			delay
+		),
+	}
+	source_state
+}
+
+// Starts from the version saved by a previous run until the source answers
+async fn load_cached(state: &AppState, dir: Option<&str>, name: &str, source: &IpListSource) {
+	let Some(dir) = dir else {
+		return;
 	};
+	if state.ip_lists.lock().unwrap().contains_key(name) {
+		return;
+	}
+	if let Some(list) = ip_source::load_cached(dir, name, source).await {
+		log::info!("loaded saved ip list {}", name);
+		state
+			.ip_lists
+			.lock()
+			.unwrap()
+			.entry(name.to_string())
+			.or_insert(list);
+	}
+}
 
+/// Probes the upstreams of every proxy with a `health_check`, each at its own interval.
+pub fn create_health_check_task(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
 	tokio::task::spawn(async move {
-		let mut interval = interval(Duration::from_secs(timeout));
+		let mut interval = interval(HEALTH_CHECK_TICK);
+		// next probe time by upstream URL
+		let mut next_probes: HashMap<String, Instant> = HashMap::new();
 
 		loop {
 			interval.tick().await;
 
-			let res = client.get(config.ip_whitelist_url.clone()).send().await;
+			let snapshot = state.snapshot();
+			let now = Instant::now();
+			next_probes.retain(|url, _| snapshot.upstreams.contains_key(url));
 
-			match res {
-				Ok(response) => {
-					let body = match response.text().await {
-						Ok(body) => body,
-						Err(e) => {
-							log::error!("Failed to parse response body: {}", e);
+			for server in &snapshot.config.http.servers {
+				for proxy in &server.proxies {
+					let Some(check) = &proxy.health_check else {
+						continue;
+					};
+					for url in proxy.upstream_urls() {
+						if next_probes.get(url).is_some_and(|next| *next > now) {
 							continue;
 						}
-					};
-
-					let ips: Vec<IpAddr> = body
-						.lines()
-						.map(|line| line.parse::<IpAddr>())
-						.filter_map(Result::ok)
-						.collect();
-
-					log::info!("updated whitelist: {:?}", ips.len());
-
-					let mut whitelist = whitelisted_ips.lock().unwrap();
-					whitelist.clear();
-					for ip in ips {
-						whitelist.insert(ip);
+						let Some(upstream) = snapshot.upstreams.get(url) else {
+							continue;
+						};
+						next_probes
+							.insert(url.to_string(), now + Duration::from_secs(check.interval));
+						tokio::task::spawn(probe(
+							snapshot.clients.for_proxy(proxy),
+							Arc::clone(upstream),
+							check.clone(),
+						));
 					}
 				}
-				Err(e) => {
-					log::error!("Failed to send GET request: {}", e);
-				}
 			}
 		}
 	})
 }
+
+async fn probe(client: HttpsClient, upstream: Arc<UpstreamState>, check: HealthCheck) {
+	let url = format!("{}{}", upstream.url.trim_end_matches('/'), check.path);
+	let uri = match url.parse::<Uri>() {
+		Ok(uri) => uri,
+		Err(e) => {
+			log::error!("invalid health check URL {}: {}", url, e);
+			return;
+		}
+	};
+
+	let timeout = Duration::from_secs(check.timeout);
+	let success = match tokio::time::timeout(timeout, client.get(uri)).await {
+		Ok(Ok(res)) if check.expects(res.status().as_u16()) => true,
+		Ok(Ok(res)) => {
+			log::debug!("health check {} returned {}", url, res.status());
+			false
+		}
+		Ok(Err(e)) => {
+			log::debug!("health check {} failed: {}", url, e);
+			false
+		}
+		Err(_) => {
+			log::debug!("health check {} timed out after {:?}", url, timeout);
+			false
+		}
+	};
+	upstream.health.record_probe(&upstream.url, success, &check);
+}
@@ -0,0 +1,224 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	net::SocketAddr,
+	sync::{Arc, Mutex, RwLock},
+	time::Instant,
+};
+
+use rustls::ServerConfig;
+use tokio::sync::Notify;
+
+use crate::{
+	access_log::AccessLogger,
+	config::{AccessLog, Configuration, Server, Tracing, ADMIN_LIST},
+	prelude::*,
+	tls::{self, CertSlot},
+	trace::Tracer,
+	usecase::{
+		access::{AccessRule, AdminEntries, AdminEntry},
+		balancer::UpstreamStates,
+		client::Clients,
+		router::Route,
+	},
+	utils::ip_list::{IpList, IpTrie},
+};
+
+/// One version of the configuration together with everything derived from it.
+///
+/// Requests hold on to the snapshot they started with, so a reload only affects new requests.
+pub struct Snapshot {
+	pub config: Configuration,
+	// server block indices by listener address
+	pub listeners: BTreeMap<SocketAddr, Vec<usize>>,
+	// access policy of the whole configuration and of each server block
+	pub access: AccessRule,
+	pub server_access: Vec<Option<AccessRule>>,
+	pub trusted_proxies: IpTrie,
+	pub access_log: Option<Arc<AccessLog>>,
+	pub tracing: Option<Arc<Tracing>>,
+	pub clients: Clients,
+	// compiled proxy routes, by server index then proxy index
+	pub routes: Vec<Vec<Route>>,
+	// state of every upstream URL, carried over from the previous snapshot
+	pub upstreams: UpstreamStates,
+	// certificates and TLS settings by server index, for server blocks with `tls`
+	pub certs: Vec<Option<Arc<CertSlot>>>,
+	pub tls_configs: Vec<Option<Arc<ServerConfig>>>,
+	// server block indices by HTTP to HTTPS redirect address
+	pub redirects: BTreeMap<SocketAddr, Vec<usize>>,
+}
+
+impl Snapshot {
+	pub fn new(config: Configuration, previous: Option<&Snapshot>) -> Result<Self> {
+		config.validate()?;
+		let listeners = config.listeners()?;
+		let redirects = config.redirect_listeners()?;
+		let certs = config
+			.http
+			.servers
+			.iter()
+			.map(|server| {
+				server
+					.tls
+					.as_ref()
+					.map(|tls| {
+						// certificates with the same files are kept, the watch task reloads them
+						let reused = previous
+							.and_then(|previous| {
+								previous.certs.iter().flatten().find(|slot| {
+									slot.tls.cert == tls.cert && slot.tls.key == tls.key
+								})
+							})
+							.map(Arc::clone);
+						match reused {
+							Some(slot) => Ok(slot),
+							None => CertSlot::load(tls)
+								.map(Arc::new)
+								.map_err(|e| Error::Generic(f!("server {:?}: {}", server.name, e))),
+						}
+					})
+					.transpose()
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let tls_configs = certs
+			.iter()
+			.zip(&config.http.servers)
+			.map(|(slot, server)| {
+				let client_auth = server.tls.as_ref().and_then(|tls| tls.client_auth.as_ref());
+				slot.clone()
+					.map(|slot| tls::server_config(slot, client_auth))
+					.transpose()
+					.map_err(|e| Error::Generic(f!("server {:?}: {}", server.name, e)))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let access = AccessRule::global(&config)?;
+		let server_access = config
+			.http
+			.servers
+			.iter()
+			.map(|server| server.access.as_ref().map(AccessRule::compile).transpose())
+			.collect::<Result<Vec<_>>>()?;
+		let trusted_proxies = config.trusted_proxy_list()?;
+		let access_log = config.access_log().map(Arc::new);
+		let tracing = config.tracing.clone().map(Arc::new);
+		let clients = Clients::from_config(&config, previous.map(|previous| &previous.clients))?;
+		let mut upstreams: UpstreamStates = previous
+			.map(|previous| previous.upstreams.clone())
+			.unwrap_or_default();
+		let routes = config
+			.http
+			.servers
+			.iter()
+			.map(|server| Route::compile_all(server, &config.timeouts, &mut upstreams))
+			.collect::<Result<Vec<_>>>()?;
+		// forget upstreams no route uses anymore
+		upstreams.retain(|url, _| {
+			config.http.servers.iter().any(|server| {
+				server
+					.proxies
+					.iter()
+					.any(|proxy| proxy.upstream_urls().contains(&url.as_str()))
+			})
+		});
+
+		Ok(Snapshot {
+			config,
+			listeners,
+			access,
+			server_access,
+			trusted_proxies,
+			access_log,
+			tracing,
+			clients,
+			routes,
+			upstreams,
+			certs,
+			tls_configs,
+			redirects,
+		})
+	}
+
+	/// Server block indices served on a listener address.
+	pub fn servers_for(&self, listener: &SocketAddr) -> &[usize] {
+		self.listeners
+			.get(listener)
+			.map(Vec::as_slice)
+			.unwrap_or_default()
+	}
+
+	/// Whether a listener address terminates TLS, which all its server blocks agree on.
+	pub fn is_tls(&self, listener: &SocketAddr) -> bool {
+		self.servers_for(listener)
+			.iter()
+			.any(|&index| self.certs[index].is_some())
+	}
+
+	/// A server block with its compiled routes.
+	pub fn server(&self, index: usize) -> (&Server, &[Route]) {
+		(&self.config.http.servers[index], &self.routes[index])
+	}
+}
+
+/// State shared by every listener and background task.
+pub struct AppState {
+	current: RwLock<Arc<Snapshot>>,
+	// lists fetched from `ip_lists` and `ip_whitelist_url`, by name, once loaded, and the admin
+	// entries
+	pub ip_lists: Mutex<HashMap<String, IpList>>,
+	admin_entries: Mutex<AdminEntries>,
+	// wakes the list updater for an immediate refresh of every source
+	pub refresh_ip_lists: Notify,
+	pub access_log: AccessLogger,
+	pub tracer: Tracer,
+}
+
+impl AppState {
+	pub fn new(snapshot: Snapshot, access_log: AccessLogger, tracer: Tracer) -> Self {
+		let admin_entries = AdminEntries::default();
+		let ip_lists = HashMap::from([(ADMIN_LIST.to_string(), admin_entries.list())]);
+		AppState {
+			current: RwLock::new(Arc::new(snapshot)),
+			ip_lists: Mutex::new(ip_lists),
+			admin_entries: Mutex::new(admin_entries),
+			refresh_ip_lists: Notify::new(),
+			access_log,
+			tracer,
+		}
+	}
+
+	/// Changes the admin entries and publishes them to `ip_lists`.
+	pub fn update_admin_entries<R>(&self, change: impl FnOnce(&mut AdminEntries) -> R) -> R {
+		let mut entries = self.admin_entries.lock().unwrap();
+		let result = change(&mut entries);
+		self.ip_lists
+			.lock()
+			.unwrap()
+			.insert(ADMIN_LIST.to_string(), entries.list());
+		result
+	}
+
+	/// Drops the expired admin entries.
+	pub fn expire_admin_entries(&self) {
+		let mut entries = self.admin_entries.lock().unwrap();
+		if entries.expire(Instant::now()) {
+			self.ip_lists
+				.lock()
+				.unwrap()
+				.insert(ADMIN_LIST.to_string(), entries.list());
+		}
+	}
+
+	pub fn admin_entries(&self) -> Vec<AdminEntry> {
+		self.admin_entries.lock().unwrap().report()
+	}
+
+	/// The configuration new requests should use.
+	pub fn snapshot(&self) -> Arc<Snapshot> {
+		Arc::clone(&self.current.read().unwrap())
+	}
+
+	/// Swaps in a new configuration and returns the previous one.
+	pub fn replace(&self, snapshot: Snapshot) -> Arc<Snapshot> {
+		std::mem::replace(&mut *self.current.write().unwrap(), Arc::new(snapshot))
+	}
+}
@@ -0,0 +1,524 @@
+use std::{
+	fs::{self, File},
+	io::BufReader,
+	net::{IpAddr, SocketAddr},
+	sync::{Arc, Mutex, RwLock},
+	time::{Duration, SystemTime},
+};
+
+use hyper::{server::conn::Http, service::service_fn};
+use ring::digest;
+use rustls::{
+	client::{ServerCertVerified, ServerCertVerifier},
+	server::{
+		Acceptor, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+		ResolvesServerCert,
+	},
+	sign::{self, CertifiedKey},
+	version::{TLS12, TLS13},
+	Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+	SupportedProtocolVersion,
+};
+use tokio::{net::TcpListener, time::interval};
+use tokio_rustls::LazyConfigAcceptor;
+use x509_parser::{
+	extensions::GeneralName,
+	prelude::{FromDer, X509Certificate},
+};
+
+use crate::{
+	config::{ClientAuth, ClientAuthMode, Server, Tls, TlsVersion, UnknownHost, UpstreamTls},
+	prelude::*,
+	state::{AppState, Snapshot},
+	usecase::{self, proxy::Connection, router},
+};
+
+// How often certificate files are checked for changes
+const CERT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Time a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Pause after a failed accept, before trying again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Certificate of a server block, swapped for new handshakes when its files change on disk.
+pub struct CertSlot {
+	pub tls: Tls,
+	key: RwLock<Arc<CertifiedKey>>,
+	// modification time and size of the certificate and key files last loaded, and of the
+	// changed files seen on the previous check
+	files: Mutex<(Option<FilesFingerprint>, Option<FilesFingerprint>)>,
+}
+
+type FilesFingerprint = [(SystemTime, u64); 2];
+
+impl CertSlot {
+	pub fn load(tls: &Tls) -> Result<Self> {
+		let files = files_fingerprint(tls);
+		let key = load_certified_key(tls)?;
+		log_expiry(&tls.cert, &key);
+		Ok(CertSlot {
+			tls: tls.clone(),
+			key: RwLock::new(Arc::new(key)),
+			files: Mutex::new((files, None)),
+		})
+	}
+
+	/// The certificate new handshakes use.
+	pub fn current(&self) -> Arc<CertifiedKey> {
+		Arc::clone(&self.key.read().unwrap())
+	}
+
+	/// Loads the files again when they changed and then stayed the same for one check, so the
+	/// certificate and key are not read while being replaced one after the other. A certificate
+	/// that fails to load is rejected and the current one kept.
+	pub fn reload_if_changed(&self) {
+		let files = files_fingerprint(&self.tls);
+		{
+			let mut guard = self.files.lock().unwrap();
+			let (loaded, pending) = &mut *guard;
+			if files.is_none() || *loaded == files {
+				*pending = None;
+				return;
+			}
+			if *pending != files {
+				*pending = files;
+				return;
+			}
+			// a failed load is not retried until the files change again
+			*loaded = files;
+			*pending = None;
+		}
+
+		match load_certified_key(&self.tls) {
+			Ok(key) => {
+				log::info!("reloaded certificate {}", self.tls.cert);
+				log_expiry(&self.tls.cert, &key);
+				*self.key.write().unwrap() = Arc::new(key);
+			}
+			Err(e) => {
+				log::error!("rejected new certificate, keeping the current one: {}", e);
+			}
+		}
+	}
+}
+
+fn files_fingerprint(tls: &Tls) -> Option<FilesFingerprint> {
+	let fingerprint = |path: &str| {
+		fs::metadata(path)
+			.ok()
+			.and_then(|meta| Some((meta.modified().ok()?, meta.len())))
+	};
+	Some([fingerprint(&tls.cert)?, fingerprint(&tls.key)?])
+}
+
+fn log_expiry(path: &str, key: &CertifiedKey) {
+	let Some(Ok((_, cert))) = key
+		.cert
+		.first()
+		.map(|cert| X509Certificate::from_der(&cert.0))
+	else {
+		return;
+	};
+	let validity = cert.validity();
+	match validity.time_to_expiration() {
+		Some(left) => log::info!(
+			"certificate {} expires in {} days ({})",
+			path,
+			left.whole_days(),
+			validity.not_after
+		),
+		None => log::warn!(
+			"certificate {} is not valid now, valid from {} to {}",
+			path,
+			validity.not_before,
+			validity.not_after
+		),
+	}
+}
+
+/// Checks the certificate files of every TLS server block and swaps the ones that changed.
+pub fn create_cert_watch_task(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+	tokio::task::spawn(async move {
+		let mut interval = interval(CERT_WATCH_INTERVAL);
+		loop {
+			interval.tick().await;
+			let snapshot = state.snapshot();
+			for slot in snapshot.certs.iter().flatten() {
+				slot.reload_if_changed();
+			}
+		}
+	})
+}
+
+/// Loads the certificate chain and private key of a server block.
+pub fn load_certified_key(tls: &Tls) -> Result<CertifiedKey> {
+	let certs = read_pem(&tls.cert, |reader| rustls_pemfile::certs(reader))?;
+	if certs.is_empty() {
+		return Err(Error::Generic(f!("no certificate in {}", tls.cert)));
+	}
+	// rustls only parses certificates during handshakes
+	for cert in &certs {
+		X509Certificate::from_der(cert)
+			.map_err(|e| Error::Generic(f!("invalid certificate in {}: {}", tls.cert, e)))?;
+	}
+	let key = read_private_key(&tls.key)?;
+	let key = sign::any_supported_type(&key)
+		.map_err(|e| Error::Generic(f!("unsupported private key in {}: {}", tls.key, e)))?;
+	Ok(CertifiedKey::new(
+		certs.into_iter().map(Certificate).collect(),
+		key,
+	))
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey> {
+	read_pem(path, |reader| {
+		let items = rustls_pemfile::read_all(reader)?;
+		Ok(items.into_iter().find_map(|item| match item {
+			rustls_pemfile::Item::RSAKey(key)
+			| rustls_pemfile::Item::PKCS8Key(key)
+			| rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+			_ => None,
+		}))
+	})?
+	.ok_or_else(|| Error::Generic(f!("no private key in {}", path)))
+}
+
+fn read_roots(path: &str) -> Result<RootCertStore> {
+	let mut roots = RootCertStore::empty();
+	for cert in read_pem(path, |reader| rustls_pemfile::certs(reader))? {
+		roots
+			.add(&Certificate(cert))
+			.map_err(|e| Error::Generic(f!("invalid CA in {}: {}", path, e)))?;
+	}
+	if roots.is_empty() {
+		return Err(Error::Generic(f!("no certificate in {}", path)));
+	}
+	Ok(roots)
+}
+
+/// Client side TLS settings for the upstreams of a proxy with `upstream_tls`.
+pub fn upstream_config(tls: &UpstreamTls) -> Result<ClientConfig> {
+	let versions: &[&SupportedProtocolVersion] = match tls.min_version {
+		TlsVersion::Tls12 => &[&TLS13, &TLS12],
+		TlsVersion::Tls13 => &[&TLS13],
+	};
+	let roots = match &tls.ca {
+		Some(ca) => read_roots(ca)?,
+		None => {
+			let mut roots = RootCertStore::empty();
+			let native = rustls_native_certs::load_native_certs()
+				.map_err(|e| Error::Generic(f!("system CA certificates: {}", e)))?;
+			for cert in native {
+				// like hyper-rustls, skip the system certificates rustls cannot use
+				let _ = roots.add(&Certificate(cert.0));
+			}
+			roots
+		}
+	};
+	let builder = ClientConfig::builder()
+		.with_safe_default_cipher_suites()
+		.with_safe_default_kx_groups()
+		.with_protocol_versions(versions)
+		.map_err(|e| Error::Generic(f!("upstream_tls: {}", e)))?
+		.with_root_certificates(roots);
+	let mut config = match (&tls.cert, &tls.key) {
+		(Some(cert), Some(key)) => {
+			let certs = read_pem(cert, |reader| rustls_pemfile::certs(reader))?;
+			builder
+				.with_client_auth_cert(
+					certs.into_iter().map(Certificate).collect(),
+					read_private_key(key)?,
+				)
+				.map_err(|e| Error::Generic(f!("client certificate {}: {}", cert, e)))?
+		}
+		_ => builder.with_no_client_auth(),
+	};
+	if tls.dangerous_skip_verify {
+		config
+			.dangerous()
+			.set_certificate_verifier(Arc::new(SkipServerVerification));
+	}
+	Ok(config)
+}
+
+/// Accepts any upstream certificate, for `dangerous_skip_verify`.
+struct SkipServerVerification;
+
+impl ServerCertVerifier for SkipServerVerification {
+	fn verify_server_cert(
+		&self,
+		_: &Certificate,
+		_: &[Certificate],
+		_: &ServerName,
+		_: &mut dyn Iterator<Item = &[u8]>,
+		_: &[u8],
+		_: SystemTime,
+	) -> std::result::Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+}
+
+fn read_pem<T>(
+	path: &str,
+	parse: impl FnOnce(&mut BufReader<File>) -> std::io::Result<T>,
+) -> Result<T> {
+	let file = File::open(path).map_err(|e| Error::Generic(f!("{}: {}", path, e)))?;
+	parse(&mut BufReader::new(file)).map_err(|e| Error::Generic(f!("{}: {}", path, e)))
+}
+
+/// Hands out the current certificate of a server block, so reloaded ones are used right away.
+struct SlotResolver(Arc<CertSlot>);
+
+impl ResolvesServerCert for SlotResolver {
+	fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+		Some(self.0.current())
+	}
+}
+
+/// TLS 1.2 and 1.3 with ALPN for HTTP/2 and HTTP/1.1, verifying client certificates when the
+/// server block has `client_auth`.
+pub fn server_config(
+	slot: Arc<CertSlot>,
+	client_auth: Option<&ClientAuth>,
+) -> Result<Arc<ServerConfig>> {
+	let builder = ServerConfig::builder().with_safe_defaults();
+	let builder = match client_auth {
+		None => builder.with_no_client_auth(),
+		Some(client_auth) => {
+			let roots = read_roots(&client_auth.ca)?;
+			let verifier = match client_auth.mode {
+				ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots).boxed(),
+				ClientAuthMode::Optional => {
+					AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+				}
+			};
+			builder.with_client_cert_verifier(verifier)
+		}
+	};
+	let mut config = builder.with_cert_resolver(Arc::new(SlotResolver(slot)));
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+	Ok(Arc::new(config))
+}
+
+/// Server block of a listener whose certificate answers an SNI name, with the same rules as
+/// the `Host` header. Clients without SNI get the default server's certificate.
+fn select_server(snapshot: &Snapshot, listener: &SocketAddr, sni: Option<&str>) -> Option<usize> {
+	let indices: Vec<usize> = snapshot
+		.servers_for(listener)
+		.iter()
+		.copied()
+		.filter(|&index| snapshot.tls_configs[index].is_some())
+		.collect();
+	let servers: Vec<&Server> = indices
+		.iter()
+		.map(|&index| &snapshot.config.http.servers[index])
+		.collect();
+	router::select_server(&servers, sni, UnknownHost::FirstServer).map(|position| indices[position])
+}
+
+/// What the TLS handshake of a connection settled.
+#[derive(Debug)]
+pub struct TlsSession {
+	// index of the server block whose certificate was presented
+	pub server: usize,
+	pub client_cert: Option<ClientCert>,
+}
+
+/// Client certificate verified during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+	pub subject: String,
+	pub sans: Vec<String>,
+	pub fingerprint: String,
+}
+
+impl ClientCert {
+	pub fn parse(der: &[u8]) -> Result<Self> {
+		let (_, cert) = X509Certificate::from_der(der)
+			.map_err(|e| Error::Generic(f!("invalid client certificate: {}", e)))?;
+		let sans = match cert.subject_alternative_name() {
+			Ok(Some(extension)) => extension
+				.value
+				.general_names
+				.iter()
+				.filter_map(|name| match name {
+					GeneralName::DNSName(dns) => Some(f!("DNS:{}", dns)),
+					GeneralName::RFC822Name(email) => Some(f!("email:{}", email)),
+					GeneralName::URI(uri) => Some(f!("URI:{}", uri)),
+					GeneralName::IPAddress(ip) => ip_address(ip).map(|ip| f!("IP:{}", ip)),
+					_ => None,
+				})
+				.collect(),
+			_ => Vec::new(),
+		};
+		let fingerprint = digest::digest(&digest::SHA256, der)
+			.as_ref()
+			.iter()
+			.map(|byte| f!("{:02x}", byte))
+			.collect();
+		Ok(ClientCert {
+			subject: cert.subject().to_string(),
+			sans,
+			fingerprint,
+		})
+	}
+
+	/// Values of the `subject_header`, `san_header` and `fingerprint_header` of `client_auth`.
+	pub fn header_values(&self) -> [String; 3] {
+		[
+			self.subject.clone(),
+			self.sans.join(", "),
+			self.fingerprint.clone(),
+		]
+	}
+}
+
+fn ip_address(bytes: &[u8]) -> Option<IpAddr> {
+	match bytes.len() {
+		4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+		16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+		_ => None,
+	}
+}
+
+/// Accepts TLS connections on `addr` and serves them like the plain listeners.
+pub async fn serve(addr: SocketAddr, state: Arc<AppState>) {
+	let listener = match TcpListener::bind(addr).await {
+		Ok(listener) => listener,
+		Err(e) => {
+			log::error!("failed to bind {}: {}", addr, e);
+			return;
+		}
+	};
+	log::info!("listening on {} (tls)", addr);
+
+	loop {
+		let (stream, remote) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => {
+				// like running out of file descriptors, retrying at once would spin
+				log::error!("failed to accept on {}: {}", addr, e);
+				tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+				continue;
+			}
+		};
+		let state = Arc::clone(&state);
+		tokio::task::spawn(async move {
+			let handshake = async {
+				// the server block is picked from the client hello, each has its own TLS settings
+				let start = match LazyConfigAcceptor::new(Acceptor::default(), stream).await {
+					Ok(start) => start,
+					Err(e) => {
+						log::debug!("tls handshake with {} failed: {}", remote, e);
+						return None;
+					}
+				};
+				let snapshot = state.snapshot();
+				let server = select_server(&snapshot, &addr, start.client_hello().server_name())?;
+				let config = snapshot.tls_configs[server].clone()?;
+				drop(snapshot);
+				match start.into_stream(config).await {
+					Ok(stream) => Some((stream, server)),
+					Err(e) => {
+						log::debug!("tls handshake with {} failed: {}", remote, e);
+						None
+					}
+				}
+			};
+			let (stream, server) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+				Ok(Some(handshake)) => handshake,
+				Ok(None) => return,
+				Err(_) => {
+					log::debug!("tls handshake with {} timed out", remote);
+					return;
+				}
+			};
+			let client_cert = stream
+				.get_ref()
+				.1
+				.peer_certificates()
+				.and_then(|certs| certs.first())
+				.and_then(|cert| match ClientCert::parse(&cert.0) {
+					Ok(cert) => Some(cert),
+					Err(e) => {
+						log::debug!("client certificate of {}: {}", remote, e);
+						None
+					}
+				});
+
+			let conn = Arc::new(Connection {
+				listener: addr,
+				remote,
+				tls: Some(TlsSession {
+					server,
+					client_cert,
+				}),
+			});
+			let service = service_fn(move |req| {
+				usecase::proxy::mirror(req, Arc::clone(&state), Arc::clone(&conn))
+			});
+			if let Err(e) = Http::new().serve_connection(stream, service).await {
+				log::debug!("connection with {} closed: {}", remote, e);
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rejects_invalid_certificates() {
+		let dir = std::env::temp_dir().join("rust-reverse-proxy-tls");
+		fs::create_dir_all(&dir).unwrap();
+		let cert = dir.join("invalid.crt");
+		fs::write(
+			&cert,
+			"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+		)
+		.unwrap();
+		let tls = Tls {
+			cert: cert.to_string_lossy().into_owned(),
+			key: dir.join("missing.key").to_string_lossy().into_owned(),
+			redirect_listen: None,
+			client_auth: None,
+		};
+		let Err(e) = load_certified_key(&tls) else {
+			panic!("invalid certificate loaded");
+		};
+		assert!(e.to_string().contains("invalid certificate"), "{}", e);
+	}
+
+	const CLIENT_CERT: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIB1TCCAXygAwIBAgIUb9LDf3/rSIzv2lVy1BK18whu2rEwCgYIKoZIzj0EAwIw
+KDEUMBIGA1UEAwwLY2xpZW50LnRlc3QxEDAOBgNVBAoMB0V4YW1wbGUwHhcNMjYx
+MDE4MDM1MTQxWhcNMzYxMDE1MDM1MTQxWjAoMRQwEgYDVQQDDAtjbGllbnQudGVz
+dDEQMA4GA1UECgwHRXhhbXBsZTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABH4d
+Kb9GiTY6e0lD0idtgWs0rjL3aSfA4T7Xy59ZrnwbmjetiUdrS8xTK6QFgU+1HO6L
+YfMhvK9sYnqA1NcMYZejgYMwgYAwHQYDVR0OBBYEFP8d0ntmxY46huxg+X8xFGWo
+dVKRMB8GA1UdIwQYMBaAFP8d0ntmxY46huxg+X8xFGWodVKRMA8GA1UdEwEB/wQF
+MAMBAf8wLQYDVR0RBCYwJIILY2xpZW50LnRlc3SHBAoAAAGBD29wc0BleGFtcGxl
+LmNvbTAKBggqhkjOPQQDAgNHADBEAiApJe8JhUHH5pIMvFKB6LdC7ivY7FRirA8I
+yRN09P4zJQIgfemNw80fN2rsHg+FlzQHIuLvs/Z/X/GruuQ1RVj2Sn0=
+-----END CERTIFICATE-----
+";
+
+	#[test]
+	fn describes_client_certificates() {
+		let der = rustls_pemfile::certs(&mut CLIENT_CERT.as_bytes()).unwrap();
+		let cert = ClientCert::parse(&der[0]).unwrap();
+		assert_eq!(cert.subject, "CN=client.test, O=Example");
+		assert_eq!(
+			cert.sans,
+			["DNS:client.test", "IP:10.0.0.1", "email:ops@example.com"]
+		);
+		assert_eq!(
+			cert.fingerprint,
+			"9875783a39f71439dbf908ee75346189e199f5aaeb22a5f4222f0aa44dc5f525"
+		);
+	}
+}
@@ -0,0 +1,574 @@
+use std::{
+	future::Future,
+	net::IpAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+	header::{HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT},
+	Body, HeaderMap, Request,
+};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{config::Tracing, prelude::*, utils::host};
+
+// Finished spans waiting to be exported, more are dropped rather than slowing requests down
+const QUEUE_SIZE: usize = 4096;
+// Spans sent to the collector at once
+const MAX_BATCH: usize = 512;
+// How long the first span of a batch waits for others
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+tokio::task_local! {
+	// span of the request being handled, parent of the spans started with `span`
+	static CURRENT: Parent;
+}
+
+/// Identity of a span as carried by the W3C `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+	pub trace_id: u128,
+	pub span_id: u64,
+	pub sampled: bool,
+}
+
+impl SpanContext {
+	/// Reads a `traceparent` header, `None` when it is not valid.
+	pub fn parse(traceparent: &str) -> Option<Self> {
+		let mut parts = traceparent.trim().split('-');
+		let version = parts.next()?;
+		let trace_id = parts.next()?;
+		let span_id = parts.next()?;
+		let flags = parts.next()?;
+		let hex = |value: &str, len: usize| {
+			value.len() == len
+				&& value
+					.bytes()
+					.all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+		};
+		// later versions may add fields, version 00 has exactly four
+		let valid = hex(version, 2)
+			&& version != "ff"
+			&& (version != "00" || parts.next().is_none())
+			&& hex(trace_id, 32)
+			&& hex(span_id, 16)
+			&& hex(flags, 2);
+		if !valid {
+			return None;
+		}
+		let context = SpanContext {
+			trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+			span_id: u64::from_str_radix(span_id, 16).ok()?,
+			sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+		};
+		(context.trace_id != 0 && context.span_id != 0).then_some(context)
+	}
+
+	pub fn traceparent(&self) -> String {
+		f!(
+			"00-{:032x}-{:016x}-{:02x}",
+			self.trace_id,
+			self.span_id,
+			u8::from(self.sampled)
+		)
+	}
+}
+
+/// OTLP span kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+	Internal = 1,
+	Server = 2,
+	Client = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+	String(String),
+	Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+	fn from(value: &str) -> Self {
+		AttributeValue::String(value.to_string())
+	}
+}
+
+impl From<String> for AttributeValue {
+	fn from(value: String) -> Self {
+		AttributeValue::String(value)
+	}
+}
+
+impl From<i64> for AttributeValue {
+	fn from(value: i64) -> Self {
+		AttributeValue::Int(value)
+	}
+}
+
+impl AttributeValue {
+	fn to_otlp(&self) -> Value {
+		match self {
+			AttributeValue::String(value) => json!({ "stringValue": value }),
+			// 64 bit integers are strings in OTLP/JSON
+			AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+		}
+	}
+}
+
+// Where a sampled span goes once it ends
+#[derive(Debug, Clone)]
+struct Sink {
+	tracer: Tracer,
+	settings: Arc<Tracing>,
+}
+
+// What the spans of a request inherit from its server span
+#[derive(Clone)]
+struct Parent {
+	context: SpanContext,
+	trace_state: Option<String>,
+	// `None` when the trace is not sampled
+	sink: Option<Sink>,
+}
+
+/// A span being recorded, exported when dropped if its trace is sampled.
+#[derive(Debug)]
+pub struct Span {
+	context: SpanContext,
+	parent: Option<u64>,
+	trace_state: Option<String>,
+	name: String,
+	kind: SpanKind,
+	start: SystemTime,
+	attributes: Vec<(&'static str, AttributeValue)>,
+	error: Option<String>,
+	sink: Option<Sink>,
+}
+
+impl Span {
+	fn child_of(parent: &Parent, name: String, kind: SpanKind) -> Self {
+		Span {
+			context: SpanContext {
+				span_id: new_span_id(),
+				..parent.context
+			},
+			parent: Some(parent.context.span_id),
+			trace_state: parent.trace_state.clone(),
+			name,
+			kind,
+			start: SystemTime::now(),
+			attributes: Vec::new(),
+			error: None,
+			sink: parent.sink.clone(),
+		}
+	}
+
+	pub fn set_name(&mut self, name: impl Into<String>) {
+		self.name = name.into();
+	}
+
+	/// Sets an attribute, replacing the previous value of `key`.
+	pub fn set(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+		let value = value.into();
+		match self.attributes.iter_mut().find(|(name, _)| *name == key) {
+			Some((_, current)) => *current = value,
+			None => self.attributes.push((key, value)),
+		}
+	}
+
+	/// Marks the span as failed.
+	pub fn set_error(&mut self, message: impl Into<String>) {
+		self.error = Some(message.into());
+	}
+
+	/// Runs `future` with this span as the parent of the spans it starts.
+	pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+		let parent = Parent {
+			context: self.context,
+			trace_state: self.trace_state.clone(),
+			sink: self.sink.clone(),
+		};
+		CURRENT.scope(parent, future).await
+	}
+
+	/// Sets `traceparent` and `tracestate` so the next hop continues the trace from this span.
+	pub fn inject(&self, headers: &mut HeaderMap) {
+		if let Ok(value) = HeaderValue::from_str(&self.context.traceparent()) {
+			headers.insert(TRACEPARENT, value);
+		}
+		match self
+			.trace_state
+			.as_deref()
+			.and_then(|state| HeaderValue::from_str(state).ok())
+		{
+			Some(value) => headers.insert(TRACESTATE, value),
+			None => headers.remove(TRACESTATE),
+		};
+	}
+
+	fn to_otlp(&self, end: SystemTime) -> Value {
+		let attributes: Vec<Value> = self
+			.attributes
+			.iter()
+			.map(|(key, value)| json!({ "key": key, "value": value.to_otlp() }))
+			.collect();
+		let mut span = json!({
+			"traceId": f!("{:032x}", self.context.trace_id),
+			"spanId": f!("{:016x}", self.context.span_id),
+			"name": self.name,
+			"kind": self.kind as u8,
+			"startTimeUnixNano": unix_nanos(self.start).to_string(),
+			"endTimeUnixNano": unix_nanos(end).to_string(),
+			"attributes": attributes,
+			"status": match &self.error {
+				Some(message) => json!({ "code": 2, "message": message }),
+				None => json!({}),
+			},
+		});
+		if let Some(parent) = self.parent {
+			span["parentSpanId"] = f!("{:016x}", parent).into();
+		}
+		if let Some(state) = &self.trace_state {
+			span["traceState"] = state.as_str().into();
+		}
+		span
+	}
+}
+
+impl Drop for Span {
+	fn drop(&mut self) {
+		if let Some(sink) = &self.sink {
+			let span = self.to_otlp(SystemTime::now());
+			sink.tracer.send(span, Arc::clone(&sink.settings));
+		}
+	}
+}
+
+/// Starts the server span of a request, continuing the trace of its `traceparent` header when
+/// it has a valid one.
+pub fn start_request(
+	req: &Request<Body>,
+	client_ip: IpAddr,
+	settings: Arc<Tracing>,
+	tracer: &Tracer,
+) -> Span {
+	let headers = req.headers();
+	let remote = headers
+		.get(TRACEPARENT)
+		.and_then(|value| value.to_str().ok())
+		.and_then(SpanContext::parse);
+	let context = remote.unwrap_or_else(|| SpanContext {
+		trace_id: fastrand::u128(1..),
+		span_id: 0,
+		sampled: fastrand::f64() < settings.sample_ratio,
+	});
+	let parent = Parent {
+		context,
+		// only kept along with a valid traceparent
+		trace_state: remote.and_then(|_| trace_state(headers)),
+		sink: context.sampled.then(|| Sink {
+			tracer: tracer.clone(),
+			settings,
+		}),
+	};
+	let mut span = Span::child_of(&parent, "request".into(), SpanKind::Server);
+	// a new trace starts with this span
+	span.parent = remote.map(|context| context.span_id);
+	span.set("http.request.method", req.method().as_str());
+	span.set("url.path", req.uri().path());
+	if let Some(query) = req.uri().query() {
+		span.set("url.query", query);
+	}
+	if let Some(host) = host::request_host(req) {
+		span.set("server.address", host);
+	}
+	span.set("client.address", client_ip.to_string());
+	span.set("network.protocol.version", f!("{:?}", req.version()));
+	if let Some(agent) = headers
+		.get(USER_AGENT)
+		.and_then(|value| value.to_str().ok())
+	{
+		span.set("user_agent.original", agent);
+	}
+	span
+}
+
+// Members of every `tracestate` header, in order
+fn trace_state(headers: &HeaderMap) -> Option<String> {
+	let members: Vec<&str> = headers
+		.get_all(TRACESTATE)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(str::trim)
+		.filter(|member| !member.is_empty())
+		.collect();
+	(!members.is_empty()).then(|| members.join(","))
+}
+
+/// Starts a child of the span of the current request, `None` outside a traced request.
+pub fn span(name: &str, kind: SpanKind) -> Option<Span> {
+	CURRENT
+		.try_with(|parent| Span::child_of(parent, name.to_string(), kind))
+		.ok()
+}
+
+fn new_span_id() -> u64 {
+	fastrand::u64(1..)
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+	time.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos()
+}
+
+/// Queues finished spans for the export task.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+	sender: mpsc::Sender<(Value, Arc<Tracing>)>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl Tracer {
+	/// Starts the task sending the spans to the collector in batches.
+	pub fn start() -> Self {
+		let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+		let dropped = Arc::new(AtomicU64::new(0));
+		tokio::task::spawn(export_spans(receiver, Arc::clone(&dropped)));
+		Tracer { sender, dropped }
+	}
+
+	fn send(&self, span: Value, settings: Arc<Tracing>) {
+		if self.sender.try_send((span, settings)).is_err() {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+async fn export_spans(
+	mut receiver: mpsc::Receiver<(Value, Arc<Tracing>)>,
+	dropped: Arc<AtomicU64>,
+) {
+	let client = reqwest::Client::new();
+	while let Some(first) = receiver.recv().await {
+		let mut batch = vec![first];
+		let deadline = tokio::time::sleep(BATCH_DELAY);
+		tokio::pin!(deadline);
+		while batch.len() < MAX_BATCH {
+			tokio::select! {
+				message = receiver.recv() => match message {
+					Some(message) => batch.push(message),
+					None => break,
+				},
+				_ = &mut deadline => break,
+			}
+		}
+		// a batch goes to the collector of the latest configuration
+		let settings = Arc::clone(&batch[batch.len() - 1].1);
+		let count = batch.len();
+		let spans = batch.into_iter().map(|(span, _)| span).collect();
+		if let Err(e) = export(&client, &settings, spans).await {
+			log::warn!(
+				"failed to export {} spans to {}: {}",
+				count,
+				settings.endpoint,
+				e
+			);
+		}
+		let dropped = dropped.swap(0, Ordering::Relaxed);
+		if dropped > 0 {
+			log::warn!("trace export queue full, dropped {} spans", dropped);
+		}
+	}
+}
+
+// Sends spans as an OTLP/JSON `ExportTraceServiceRequest`
+async fn export(
+	client: &reqwest::Client,
+	settings: &Tracing,
+	spans: Vec<Value>,
+) -> std::result::Result<(), String> {
+	let body = json!({
+		"resourceSpans": [{
+			"resource": {
+				"attributes": [{
+					"key": "service.name",
+					"value": AttributeValue::from(settings.service_name.as_str()).to_otlp(),
+				}],
+			},
+			"scopeSpans": [{
+				"scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+				"spans": spans,
+			}],
+		}],
+	});
+	let mut request = client
+		.post(&settings.endpoint)
+		.timeout(EXPORT_TIMEOUT)
+		.header(CONTENT_TYPE, "application/json")
+		.body(body.to_string());
+	for (name, value) in &settings.headers {
+		// validated with the configuration
+		if let (Ok(name), Ok(value)) = (name.parse::<HeaderName>(), value.parse::<HeaderValue>()) {
+			request = request.header(name, value);
+		}
+	}
+	let res = request.send().await.map_err(|e| e.to_string())?;
+	if !res.status().is_success() {
+		return Err(f!("status {}", res.status()));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::OtlpProtocol;
+
+	fn settings(endpoint: &str) -> Arc<Tracing> {
+		Arc::new(Tracing {
+			endpoint: endpoint.into(),
+			protocol: OtlpProtocol::HttpJson,
+			service_name: "proxy".into(),
+			sample_ratio: 1.0,
+			headers: [("x-api-key".to_string(), "k".to_string())].into(),
+		})
+	}
+
+	#[test]
+	fn parses_traceparent() {
+		let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+		let context = SpanContext::parse(header).unwrap();
+		assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+		assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+		assert!(context.sampled);
+		assert_eq!(context.traceparent(), header);
+
+		assert!(
+			SpanContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x")
+				.is_some_and(|context| !context.sampled)
+		);
+		for invalid in [
+			"00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+			"00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+			"ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+			"00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+			"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+			"00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+		] {
+			assert_eq!(SpanContext::parse(invalid), None, "{}", invalid);
+		}
+	}
+
+	#[tokio::test]
+	async fn continues_incoming_traces() {
+		let tracer = Tracer::start();
+		let client_ip = "203.0.113.7".parse().unwrap();
+		let req = Request::builder()
+			.uri("/api/items?page=2")
+			.header(
+				TRACEPARENT,
+				"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+			)
+			.header(TRACESTATE, "vendor=a")
+			.body(Body::empty())
+			.unwrap();
+		let settings = settings("http://127.0.0.1:9/v1/traces");
+		let server = start_request(&req, client_ip, Arc::clone(&settings), &tracer);
+		assert_eq!(
+			server.context.trace_id,
+			0x4bf92f3577b34da6a3ce929d0e0e4736
+		);
+		assert_eq!(server.parent, Some(0x00f067aa0ba902b7));
+
+		let upstream = server
+			.scope(async { span("upstream", SpanKind::Client).unwrap() })
+			.await;
+		assert_eq!(upstream.parent, Some(server.context.span_id));
+		let mut forwarded = HeaderMap::new();
+		upstream.inject(&mut forwarded);
+		assert_eq!(forwarded[TRACEPARENT], upstream.context.traceparent());
+		assert_eq!(forwarded[TRACESTATE], "vendor=a");
+		assert!(span("outside", SpanKind::Internal).is_none());
+
+		let new = start_request(&Request::new(Body::empty()), client_ip, settings, &tracer);
+		assert_ne!(new.context.trace_id, server.context.trace_id);
+		assert_eq!(new.parent, None);
+	}
+
+	#[tokio::test]
+	async fn exports_to_a_collector() {
+		use hyper::{
+			service::{make_service_fn, service_fn},
+			Response, Server,
+		};
+
+		// stub collector handing over the first request it gets
+		let (sender, mut received) = mpsc::channel(1);
+		let make_svc = make_service_fn(move |_| {
+			let sender = sender.clone();
+			async move {
+				Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+					let sender = sender.clone();
+					async move {
+						let (parts, body) = req.into_parts();
+						let body = hyper::body::to_bytes(body).await?;
+						let _ = sender.send((parts, body)).await;
+						Ok::<_, hyper::Error>(Response::new(Body::from("{}")))
+					}
+				}))
+			}
+		});
+		let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+		let endpoint = f!("http://{}/v1/traces", server.local_addr());
+		tokio::spawn(server);
+
+		let mut span = Span::child_of(
+			&Parent {
+				context: SpanContext {
+					trace_id: 1,
+					span_id: 2,
+					sampled: true,
+				},
+				trace_state: None,
+				sink: None,
+			},
+			"GET /api".into(),
+			SpanKind::Server,
+		);
+		span.set("http.response.status_code", 502_i64);
+		span.set_error("bad gateway");
+		let spans = vec![span.to_otlp(SystemTime::now())];
+		export(&reqwest::Client::new(), &settings(&endpoint), spans)
+			.await
+			.unwrap();
+
+		let (parts, body) = received.recv().await.unwrap();
+		assert_eq!(parts.uri.path(), "/v1/traces");
+		assert_eq!(parts.headers["x-api-key"], "k");
+		let body: Value = serde_json::from_slice(&body).unwrap();
+		let resource = &body["resourceSpans"][0];
+		assert_eq!(
+			resource["resource"]["attributes"][0]["value"]["stringValue"],
+			"proxy"
+		);
+		let exported = &resource["scopeSpans"][0]["spans"][0];
+		assert_eq!(exported["traceId"], "00000000000000000000000000000001");
+		assert_eq!(exported["parentSpanId"], "0000000000000002");
+		assert_eq!(exported["name"], "GET /api");
+		assert_eq!(exported["kind"], 2);
+		assert_eq!(exported["attributes"][0]["value"]["intValue"], "502");
+		assert_eq!(exported["status"]["code"], 2);
+	}
+}
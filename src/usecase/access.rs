@@ -0,0 +1,248 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	net::IpAddr,
+	time::{Duration, Instant},
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{
+	config::{AccessPolicy, Configuration, Forbidden, ADMIN_LIST, WHITELIST_LIST},
+	prelude::*,
+	utils::ip_list::{Cidr, IpList},
+};
+
+/// Compiled access policy of one level.
+#[derive(Debug, Default)]
+pub struct AccessRule {
+	list: IpList,
+	// names of the remote lists added to `list`
+	lists: Vec<String>,
+	inherit: bool,
+	forbidden: Option<Forbidden>,
+}
+
+impl AccessRule {
+	pub fn compile(policy: &AccessPolicy) -> Result<Self> {
+		Ok(AccessRule {
+			list: policy.ip_list()?,
+			lists: policy.lists.clone(),
+			inherit: policy.inherit,
+			forbidden: policy.forbidden.clone(),
+		})
+	}
+
+	/// The configuration level: its `access` with `default_ip_whitelist`, `default_ip_denylist`,
+	/// the `ip_whitelist_url` list and the entries added through the admin API.
+	pub fn global(config: &Configuration) -> Result<Self> {
+		let mut rule = match &config.access {
+			Some(policy) => AccessRule::compile(policy)?,
+			None => AccessRule::default(),
+		};
+		rule.list.merge(&config.default_ip_list()?);
+		if !config.ip_whitelist_url.is_empty() {
+			rule.lists.push(WHITELIST_LIST.to_string());
+		}
+		rule.lists.push(ADMIN_LIST.to_string());
+		Ok(rule)
+	}
+
+	/// Whether `ip` passes this level. Denied entries win; when the level allows some entries,
+	/// `ip` must be one of them. A referenced list that was not fetched yet allows no one.
+	fn allows(&self, ip: IpAddr, ip_lists: &HashMap<String, IpList>) -> bool {
+		let mut lists = vec![&self.list];
+		let mut missing = false;
+		for name in &self.lists {
+			match ip_lists.get(name) {
+				Some(list) => lists.push(list),
+				None => missing = true,
+			}
+		}
+
+		if lists.iter().any(|list| list.deny.contains(ip)) {
+			return false;
+		}
+		let restricted = missing || lists.iter().any(|list| !list.allow.is_empty());
+		!restricted || lists.iter().any(|list| list.allow.contains(ip))
+	}
+}
+
+/// Checks `ip` against the rules of the levels a request goes through, outermost first.
+/// Returns the response for a rejected client.
+pub fn check(
+	ip: IpAddr,
+	levels: &[Option<&AccessRule>],
+	ip_lists: &HashMap<String, IpList>,
+) -> Option<Response<Body>> {
+	let rules: Vec<&AccessRule> = levels.iter().flatten().copied().collect();
+	// a level that does not inherit drops the ones above it
+	let first = rules.iter().rposition(|rule| !rule.inherit).unwrap_or(0);
+	let rejected = rules[first..]
+		.iter()
+		.find(|rule| !rule.allows(ip, ip_lists))?;
+	Some(forbidden_response(rejected.forbidden.as_ref()))
+}
+
+/// Whitelist entries added through the admin API, published in `ip_lists` under [`ADMIN_LIST`].
+#[derive(Debug, Default)]
+pub struct AdminEntries {
+	// expiry by entry, `!` marking a denied one
+	entries: BTreeMap<String, Option<Instant>>,
+}
+
+/// An admin entry as listed by the admin API.
+#[derive(Debug, Serialize)]
+pub struct AdminEntry {
+	pub entry: String,
+	// seconds left before the entry is dropped
+	pub expires_in: Option<u64>,
+}
+
+impl AdminEntries {
+	/// Adds an entry, or replaces its expiry when it is already there. Returns the entry in its
+	/// canonical form.
+	pub fn add(
+		&mut self,
+		entry: &str,
+		ttl: Option<Duration>,
+	) -> std::result::Result<String, String> {
+		let entry = canonical_entry(entry)?;
+		self.entries
+			.insert(entry.clone(), ttl.map(|ttl| Instant::now() + ttl));
+		Ok(entry)
+	}
+
+	/// Removes an entry, returns false when it was not there.
+	pub fn remove(&mut self, entry: &str) -> bool {
+		canonical_entry(entry).is_ok_and(|entry| self.entries.remove(&entry).is_some())
+	}
+
+	/// Drops the entries expired at `now`, returns false when there were none.
+	pub fn expire(&mut self, now: Instant) -> bool {
+		let before = self.entries.len();
+		self.entries
+			.retain(|_, expires| expires.is_none_or(|expires| expires > now));
+		self.entries.len() != before
+	}
+
+	pub fn list(&self) -> IpList {
+		let mut list = IpList::default();
+		for entry in self.entries.keys() {
+			// entries are validated when added
+			let _ = list.add(entry);
+		}
+		list
+	}
+
+	pub fn report(&self) -> Vec<AdminEntry> {
+		let now = Instant::now();
+		self.entries
+			.iter()
+			.map(|(entry, expires)| AdminEntry {
+				entry: entry.clone(),
+				expires_in: expires.map(|expires| expires.saturating_duration_since(now).as_secs()),
+			})
+			.collect()
+	}
+}
+
+// `10.1.2.3/8` and `10.0.0.0/8` are the same entry
+fn canonical_entry(entry: &str) -> std::result::Result<String, String> {
+	let entry = entry.trim();
+	match entry.strip_prefix('!') {
+		Some(denied) => Ok(f!("!{}", denied.trim().parse::<Cidr>()?)),
+		None => Ok(entry.parse::<Cidr>()?.to_string()),
+	}
+}
+
+fn forbidden_response(forbidden: Option<&Forbidden>) -> Response<Body> {
+	let (content_type, body) = match forbidden {
+		Some(forbidden) => (forbidden.content_type.as_str(), forbidden.body.clone()),
+		None => ("text/plain; charset=utf-8", "Forbidden".to_string()),
+	};
+	Response::builder()
+		.status(StatusCode::FORBIDDEN)
+		.header(CONTENT_TYPE, content_type)
+		.body(body.into())
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rule(json: &str) -> AccessRule {
+		AccessRule::compile(&serde_json::from_str(json).unwrap()).unwrap()
+	}
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn every_inherited_level_must_pass() {
+		let global = rule(r#"{"deny": ["10.6.0.0/16"]}"#);
+		let server = rule(r#"{"allow": ["10.0.0.0/8"]}"#);
+		let admin = rule(r#"{"allow": ["10.1.0.0/16"], "forbidden": {"body": "office only"}}"#);
+		let public = rule(r#"{"inherit": false}"#);
+		let lists = HashMap::new();
+		let check = |ip, levels: &[Option<&AccessRule>]| check(ip, levels, &lists);
+
+		assert!(check(ip("10.2.0.1"), &[Some(&global), Some(&server), None]).is_none());
+		assert!(check(ip("10.6.0.1"), &[Some(&global), Some(&server), None]).is_some());
+		assert!(check(ip("192.0.2.1"), &[Some(&global), Some(&server), None]).is_some());
+		assert!(check(
+			ip("10.1.0.1"),
+			&[Some(&global), Some(&server), Some(&admin)]
+		)
+		.is_none());
+		let res = check(
+			ip("10.2.0.1"),
+			&[Some(&global), Some(&server), Some(&admin)],
+		)
+		.unwrap();
+		assert_eq!(res.status(), StatusCode::FORBIDDEN);
+		// a route that does not inherit is open to everyone, denied ones included
+		assert!(check(
+			ip("10.6.0.1"),
+			&[Some(&global), Some(&server), Some(&public)]
+		)
+		.is_none());
+	}
+
+	#[test]
+	fn remote_lists_fail_closed() {
+		let partners = rule(r#"{"lists": ["partners"]}"#);
+		let mut lists = HashMap::new();
+		assert!(check(ip("192.0.2.1"), &[Some(&partners)], &lists).is_some());
+
+		lists.insert("partners".to_string(), IpList::parse("192.0.2.0/24").0);
+		assert!(check(ip("192.0.2.1"), &[Some(&partners)], &lists).is_none());
+		assert!(check(ip("198.51.100.1"), &[Some(&partners)], &lists).is_some());
+	}
+
+	#[test]
+	fn admin_entries_expire() {
+		let mut entries = AdminEntries::default();
+		assert_eq!(entries.add("10.1.2.3/8", None).unwrap(), "10.0.0.0/8");
+		assert_eq!(
+			entries
+				.add(" ! 192.0.2.1", Some(Duration::from_secs(60)))
+				.unwrap(),
+			"!192.0.2.1"
+		);
+		entries
+			.add("198.51.100.0/24", Some(Duration::ZERO))
+			.unwrap();
+		assert!(entries.add("nope", None).is_err());
+
+		assert!(entries.expire(Instant::now()));
+		assert!(!entries.expire(Instant::now()));
+		let list = entries.list();
+		assert_eq!((list.allow.len(), list.deny.len()), (1, 1));
+		assert!(entries.remove("10.9.9.9/8"));
+		assert!(!entries.remove("10.0.0.0/8"));
+		assert_eq!(entries.report()[0].entry, "!192.0.2.1");
+	}
+}
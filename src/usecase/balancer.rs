@@ -0,0 +1,473 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+};
+
+use futures::StreamExt;
+use hyper::{Body, Response};
+
+use super::{circuit_breaker::Circuit, health::Health};
+use crate::config::{CircuitBreaker, HashKey, LoadBalancing, Proxy};
+
+// Points on the hash ring for every unit of weight
+const RING_POINTS_PER_WEIGHT: u32 = 100;
+
+/// Runtime state of one upstream URL, shared by every route using it and kept across reloads.
+#[derive(Debug)]
+pub struct UpstreamState {
+	pub url: String,
+	active: AtomicUsize,
+	// set through the admin API, a drained upstream gets no new requests
+	drained: AtomicBool,
+	pub health: Health,
+	pub circuit: Circuit,
+}
+
+impl UpstreamState {
+	pub fn new(url: &str) -> Self {
+		UpstreamState {
+			url: url.to_string(),
+			active: AtomicUsize::new(0),
+			drained: AtomicBool::new(false),
+			health: Health::default(),
+			circuit: Circuit::default(),
+		}
+	}
+
+	/// Requests currently being served by this upstream.
+	pub fn active(&self) -> usize {
+		self.active.load(Ordering::Relaxed)
+	}
+
+	pub fn is_drained(&self) -> bool {
+		self.drained.load(Ordering::Relaxed)
+	}
+
+	/// Stops or resumes sending new requests, the ones in flight are not affected.
+	pub fn set_drained(&self, drained: bool) {
+		self.drained.store(drained, Ordering::Relaxed);
+	}
+
+	/// Counts a request as active until the returned guard is dropped.
+	pub fn begin(self: &Arc<Self>) -> ActiveRequest {
+		self.active.fetch_add(1, Ordering::Relaxed);
+		ActiveRequest(Arc::clone(self))
+	}
+}
+
+/// Keeps a request counted in [`UpstreamState::active`] while alive.
+#[derive(Debug)]
+pub struct ActiveRequest(Arc<UpstreamState>);
+
+impl Drop for ActiveRequest {
+	fn drop(&mut self) {
+		self.0.active.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// Wraps the body of an upstream response so `active` is dropped once the body is fully sent
+/// or the client goes away.
+pub fn hold_until_body_end(res: Response<Body>, active: ActiveRequest) -> Response<Body> {
+	let (parts, body) = res.into_parts();
+	let body = Body::wrap_stream(body.map(move |chunk| {
+		let _ = &active;
+		chunk
+	}));
+	Response::from_parts(parts, body)
+}
+
+/// Upstream states by URL.
+pub type UpstreamStates = HashMap<String, Arc<UpstreamState>>;
+
+#[derive(Debug)]
+pub struct Target {
+	pub url: String,
+	pub weight: u32,
+	pub backup: bool,
+	pub state: Arc<UpstreamState>,
+}
+
+/// Picks an upstream target for each request of a route.
+#[derive(Debug)]
+pub struct Balancer {
+	targets: Vec<Target>,
+	strategy: LoadBalancing,
+	hash_key: HashKey,
+	circuit_breaker: Option<CircuitBreaker>,
+	next: AtomicUsize,
+	// smooth weighted round-robin state, by target index
+	current_weights: Mutex<Vec<i64>>,
+	// (point, target index) sorted by point
+	ring: Vec<(u64, usize)>,
+}
+
+impl Balancer {
+	/// Creates the balancer of a proxy, taking existing upstream states from `states` and adding
+	/// the missing ones.
+	pub fn new(proxy: &Proxy, states: &mut UpstreamStates) -> Self {
+		let mut state_for = |url: &str| {
+			Arc::clone(
+				states
+					.entry(url.to_string())
+					.or_insert_with(|| Arc::new(UpstreamState::new(url))),
+			)
+		};
+
+		let targets: Vec<Target> = if proxy.upstreams.is_empty() {
+			vec![Target {
+				url: proxy.proxy_pass.clone(),
+				weight: 1,
+				backup: false,
+				state: state_for(&proxy.proxy_pass),
+			}]
+		} else {
+			proxy
+				.upstreams
+				.iter()
+				.map(|upstream| Target {
+					url: upstream.url.clone(),
+					weight: upstream.weight.max(1),
+					backup: upstream.backup,
+					state: state_for(&upstream.url),
+				})
+				.collect()
+		};
+
+		let ring = if proxy.load_balancing == LoadBalancing::ConsistentHash {
+			build_ring(&targets)
+		} else {
+			Vec::new()
+		};
+
+		Balancer {
+			current_weights: Mutex::new(vec![0; targets.len()]),
+			targets,
+			strategy: proxy.load_balancing,
+			hash_key: proxy.hash_key.clone(),
+			circuit_breaker: proxy.circuit_breaker.clone(),
+			next: AtomicUsize::new(0),
+			ring,
+		}
+	}
+
+	pub fn targets(&self) -> &[Target] {
+		&self.targets
+	}
+
+	pub fn strategy(&self) -> LoadBalancing {
+		self.strategy
+	}
+
+	pub fn hash_key(&self) -> &HashKey {
+		&self.hash_key
+	}
+
+	pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+		self.circuit_breaker.as_ref()
+	}
+
+	// Healthy and not cut off by its circuit breaker
+	fn is_available(&self, target: &Target) -> bool {
+		target.state.health.is_available()
+			&& self
+				.circuit_breaker
+				.as_ref()
+				.is_none_or(|breaker| target.state.circuit.allows_traffic(breaker))
+	}
+
+	/// Picks a target index, skipping `exclude` and drained targets. Backup targets are only used
+	/// when no healthy primary target is left, and unhealthy targets only when no healthy one is
+	/// left at all.
+	/// `key` is the value hashed by `consistent_hash`.
+	pub fn pick(&self, key: Option<&str>, exclude: &[usize]) -> Option<usize> {
+		let candidates = self.candidates(exclude);
+		match candidates.len() {
+			0 => return None,
+			1 => return Some(candidates[0]),
+			_ => {}
+		}
+
+		match self.strategy {
+			LoadBalancing::RoundRobin => Some(self.round_robin(&candidates)),
+			LoadBalancing::WeightedRoundRobin => Some(self.weighted_round_robin(&candidates)),
+			LoadBalancing::LeastConnections => Some(self.least_connections(&candidates)),
+			LoadBalancing::RandomTwoChoices => Some(self.random_two_choices(&candidates)),
+			LoadBalancing::ConsistentHash => match key {
+				Some(key) => self.consistent_hash(key, &candidates),
+				None => Some(self.round_robin(&candidates)),
+			},
+		}
+	}
+
+	fn candidates(&self, exclude: &[usize]) -> Vec<usize> {
+		let usable = |backup: bool, healthy_only: bool| -> Vec<usize> {
+			self.targets
+				.iter()
+				.enumerate()
+				.filter(|(index, target)| {
+					target.backup == backup
+						&& !exclude.contains(index)
+						&& !target.state.is_drained()
+						&& (!healthy_only || self.is_available(target))
+				})
+				.map(|(index, _)| index)
+				.collect()
+		};
+		// sending traffic to unhealthy upstreams beats failing every request
+		[(false, true), (true, true), (false, false), (true, false)]
+			.into_iter()
+			.map(|(backup, healthy_only)| usable(backup, healthy_only))
+			.find(|candidates| !candidates.is_empty())
+			.unwrap_or_default()
+	}
+
+	fn round_robin(&self, candidates: &[usize]) -> usize {
+		candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+	}
+
+	// nginx's smooth weighted round-robin, spreads heavier targets instead of sending bursts
+	fn weighted_round_robin(&self, candidates: &[usize]) -> usize {
+		let mut current = self.current_weights.lock().unwrap();
+		let mut total = 0;
+		let mut best = candidates[0];
+		for &index in candidates {
+			let weight = self.targets[index].weight as i64;
+			current[index] += weight;
+			total += weight;
+			if current[index] > current[best] {
+				best = index;
+			}
+		}
+		current[best] -= total;
+		best
+	}
+
+	fn least_connections(&self, candidates: &[usize]) -> usize {
+		// start at a rotating offset so ties are spread
+		let offset = self.next.fetch_add(1, Ordering::Relaxed);
+		(0..candidates.len())
+			.map(|i| candidates[(offset + i) % candidates.len()])
+			.reduce(|best, index| {
+				if self.load_below(index, best) {
+					index
+				} else {
+					best
+				}
+			})
+			.unwrap_or(candidates[0])
+	}
+
+	fn random_two_choices(&self, candidates: &[usize]) -> usize {
+		let first = fastrand::usize(..candidates.len());
+		let mut second = fastrand::usize(..candidates.len() - 1);
+		if second >= first {
+			second += 1;
+		}
+		let (first, second) = (candidates[first], candidates[second]);
+		if self.load_below(second, first) {
+			second
+		} else {
+			first
+		}
+	}
+
+	fn consistent_hash(&self, key: &str, candidates: &[usize]) -> Option<usize> {
+		if self.ring.is_empty() {
+			return None;
+		}
+		let hash = hash(key.as_bytes());
+		let start = self.ring.partition_point(|(point, _)| *point < hash);
+		// walk clockwise until a usable target is found
+		(0..self.ring.len())
+			.map(|i| self.ring[(start + i) % self.ring.len()].1)
+			.find(|index| candidates.contains(index))
+	}
+
+	// Active requests relative to weight, `a` < `b`
+	fn load_below(&self, a: usize, b: usize) -> bool {
+		let (a, b) = (&self.targets[a], &self.targets[b]);
+		(a.state.active() as u64) * (b.weight as u64)
+			< (b.state.active() as u64) * (a.weight as u64)
+	}
+}
+
+fn build_ring(targets: &[Target]) -> Vec<(u64, usize)> {
+	let mut ring = Vec::new();
+	for (index, target) in targets.iter().enumerate() {
+		for point in 0..target.weight.saturating_mul(RING_POINTS_PER_WEIGHT) {
+			ring.push((hash(format!("{}#{}", target.url, point).as_bytes()), index));
+		}
+	}
+	ring.sort_unstable();
+	ring
+}
+
+// FNV-1a followed by a splitmix64 finalizer, stable across builds unlike std's hasher
+fn hash(bytes: &[u8]) -> u64 {
+	let mut hash: u64 = 0xcbf29ce484222325;
+	for byte in bytes {
+		hash ^= *byte as u64;
+		hash = hash.wrapping_mul(0x100000001b3);
+	}
+	hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+	hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::{PassiveHealthCheck, Upstream};
+
+	fn balancer(strategy: LoadBalancing, upstreams: &[(&str, u32, bool)]) -> Balancer {
+		let proxy = Proxy {
+			proxy_path: "/".into(),
+			upstreams: upstreams
+				.iter()
+				.map(|(url, weight, backup)| Upstream {
+					url: url.to_string(),
+					weight: *weight,
+					backup: *backup,
+				})
+				.collect(),
+			load_balancing: strategy,
+			..Default::default()
+		};
+		Balancer::new(&proxy, &mut HashMap::new())
+	}
+
+	fn picks(balancer: &Balancer, count: usize) -> Vec<usize> {
+		(0..count)
+			.map(|_| balancer.pick(None, &[]).unwrap())
+			.collect()
+	}
+
+	#[test]
+	fn round_robin_skips_backups() {
+		let balancer = balancer(
+			LoadBalancing::RoundRobin,
+			&[
+				("http://a", 1, false),
+				("http://b", 1, false),
+				("http://c", 1, true),
+			],
+		);
+		assert_eq!(picks(&balancer, 4), vec![0, 1, 0, 1]);
+		assert_eq!(balancer.pick(None, &[0, 1]), Some(2));
+		assert_eq!(balancer.pick(None, &[0, 1, 2]), None);
+	}
+
+	#[test]
+	fn skips_unhealthy_targets() {
+		let balancer = balancer(
+			LoadBalancing::RoundRobin,
+			&[
+				("http://a", 1, false),
+				("http://b", 1, false),
+				("http://c", 1, true),
+			],
+		);
+		let passive = PassiveHealthCheck {
+			max_failures: 1,
+			ejection_time: 30,
+		};
+		let eject = |index: usize| {
+			let state = &balancer.targets()[index].state;
+			state
+				.health
+				.record_request(&state.url, true, Some(&passive));
+		};
+
+		eject(0);
+		assert_eq!(picks(&balancer, 3), vec![1, 1, 1]);
+		eject(1);
+		assert_eq!(picks(&balancer, 2), vec![2, 2]);
+		// every target down, traffic goes to the primary ones
+		eject(2);
+		assert_eq!(picks(&balancer, 2), vec![0, 1]);
+	}
+
+	#[test]
+	fn never_picks_drained_targets() {
+		let balancer = balancer(
+			LoadBalancing::RoundRobin,
+			&[("http://a", 1, false), ("http://b", 1, true)],
+		);
+		balancer.targets()[0].state.set_drained(true);
+		assert_eq!(picks(&balancer, 2), vec![1, 1]);
+		balancer.targets()[1].state.set_drained(true);
+		assert_eq!(balancer.pick(None, &[]), None);
+		balancer.targets()[0].state.set_drained(false);
+		assert_eq!(picks(&balancer, 2), vec![0, 0]);
+	}
+
+	#[test]
+	fn weighted_round_robin_is_smooth() {
+		let balancer = balancer(
+			LoadBalancing::WeightedRoundRobin,
+			&[
+				("http://a", 5, false),
+				("http://b", 1, false),
+				("http://c", 1, false),
+			],
+		);
+		assert_eq!(picks(&balancer, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+	}
+
+	#[test]
+	fn least_connections_prefers_idle_targets() {
+		let balancer = balancer(
+			LoadBalancing::LeastConnections,
+			&[("http://a", 1, false), ("http://b", 1, false)],
+		);
+		let _busy = balancer.targets()[0].state.begin();
+		assert_eq!(picks(&balancer, 3), vec![1, 1, 1]);
+
+		let balancer = self::balancer(
+			LoadBalancing::RandomTwoChoices,
+			&[("http://a", 1, false), ("http://b", 1, false)],
+		);
+		let _busy = balancer.targets()[1].state.begin();
+		assert_eq!(picks(&balancer, 3), vec![0, 0, 0]);
+	}
+
+	#[test]
+	fn consistent_hash_is_sticky() {
+		let balancer = balancer(
+			LoadBalancing::ConsistentHash,
+			&[
+				("http://a", 1, false),
+				("http://b", 1, false),
+				("http://c", 1, false),
+			],
+		);
+		let first = balancer.pick(Some("visitor-1"), &[]).unwrap();
+		for _ in 0..10 {
+			assert_eq!(balancer.pick(Some("visitor-1"), &[]), Some(first));
+		}
+		// an excluded target moves its keys to another one
+		let moved = balancer.pick(Some("visitor-1"), &[first]).unwrap();
+		assert_ne!(moved, first);
+
+		let spread: std::collections::HashSet<usize> = (0..100)
+			.map(|i| balancer.pick(Some(&format!("visitor-{}", i)), &[]).unwrap())
+			.collect();
+		assert_eq!(spread.len(), 3);
+	}
+
+	#[test]
+	fn states_are_shared_by_url() {
+		let mut states = HashMap::new();
+		let proxy = Proxy {
+			proxy_pass: "http://a".into(),
+			..Default::default()
+		};
+		let first = Balancer::new(&proxy, &mut states);
+		let second = Balancer::new(&proxy, &mut states);
+		let _active = first.targets()[0].state.begin();
+		assert_eq!(second.targets()[0].state.active(), 1);
+	}
+}
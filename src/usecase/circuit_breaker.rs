@@ -0,0 +1,277 @@
+use std::{
+	path::Path,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use hyper::{
+	header::{CONTENT_TYPE, RETRY_AFTER},
+	Body, Response, StatusCode,
+};
+use mime_guess::from_path;
+use serde::Serialize;
+
+use crate::config::{CircuitBreaker, Server};
+
+// Half-open trials that never report back (e.g. cancelled requests) are given up on after this
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Circuit breaker of one upstream.
+///
+/// Closed, requests flow and their outcome is counted over a sliding window of one second
+/// buckets. Too many failures (errors, 5xx or slow responses) open the circuit: requests are
+/// rejected until `open_duration` has passed, then a few trial requests are let through
+/// (half-open) to decide whether to close it again.
+#[derive(Debug, Default)]
+pub struct Circuit {
+	inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+	#[default]
+	Closed,
+	Open,
+	HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+	state: CircuitState,
+	// when the circuit was last opened or switched to half-open
+	since: Option<Instant>,
+	// trial requests let through while half-open, and those that succeeded
+	trials: u32,
+	successes: u32,
+	// (second, requests, failures), indexed by second modulo the window length
+	buckets: Vec<(u64, u32, u32)>,
+	epoch: Option<Instant>,
+}
+
+impl Circuit {
+	pub fn state(&self) -> CircuitState {
+		self.inner.lock().unwrap().state
+	}
+
+	/// Whether the balancer may pick the upstream: closed, or open for long enough to try it.
+	pub fn allows_traffic(&self, config: &CircuitBreaker) -> bool {
+		let inner = self.inner.lock().unwrap();
+		match inner.state {
+			CircuitState::Closed | CircuitState::HalfOpen => true,
+			CircuitState::Open => inner.open_time_left(config).is_zero(),
+		}
+	}
+
+	/// Lets a request through or returns how long until the circuit may close.
+	pub fn admit(&self, url: &str, config: &CircuitBreaker) -> Result<(), Duration> {
+		let mut inner = self.inner.lock().unwrap();
+		let now = Instant::now();
+		match inner.state {
+			CircuitState::Closed => Ok(()),
+			CircuitState::Open => {
+				let left = inner.open_time_left(config);
+				if !left.is_zero() {
+					return Err(left);
+				}
+				log::info!("circuit of upstream {} is half-open", url);
+				inner.state = CircuitState::HalfOpen;
+				inner.since = Some(now);
+				inner.trials = 1;
+				inner.successes = 0;
+				Ok(())
+			}
+			CircuitState::HalfOpen => {
+				let stale = inner.since.is_some_and(|since| now - since > TRIAL_TIMEOUT);
+				if stale {
+					inner.since = Some(now);
+					inner.trials = 0;
+					inner.successes = 0;
+				}
+				if inner.trials < config.half_open_requests {
+					inner.trials += 1;
+					Ok(())
+				} else {
+					Err(Duration::from_secs(1))
+				}
+			}
+		}
+	}
+
+	/// Records the outcome of a request let through by [`Circuit::admit`].
+	pub fn record(&self, url: &str, config: &CircuitBreaker, failed: bool, latency: Duration) {
+		let failed = failed
+			|| config
+				.slow_call
+				.is_some_and(|slow| latency >= Duration::from_millis(slow));
+		let mut inner = self.inner.lock().unwrap();
+		let now = Instant::now();
+		match inner.state {
+			CircuitState::Closed => {
+				inner.count(now, config, failed);
+				let (requests, failures) = inner.totals(now, config);
+				if requests >= config.min_requests
+					&& failures as u64 * 100 >= config.error_rate as u64 * requests as u64
+				{
+					log::warn!(
+						"circuit of upstream {} opened, {} of {} requests failed in {}s",
+						url,
+						failures,
+						requests,
+						config.window
+					);
+					inner.open(now);
+				}
+			}
+			CircuitState::HalfOpen if failed => {
+				log::warn!(
+					"circuit of upstream {} opened again, trial request failed",
+					url
+				);
+				inner.open(now);
+			}
+			CircuitState::HalfOpen => {
+				inner.successes += 1;
+				if inner.successes >= config.half_open_requests {
+					log::info!("circuit of upstream {} closed", url);
+					*inner = Inner::default();
+				}
+			}
+			// requests admitted before the circuit opened
+			CircuitState::Open => {}
+		}
+	}
+}
+
+/// `503 Service Unavailable` sent while a circuit is open, with the `fallback` file as body when
+/// there is one.
+pub async fn open_response(
+	config: &CircuitBreaker,
+	retry_after: Duration,
+	server: &Server,
+) -> Response<Body> {
+	// whole seconds, rounded up
+	let seconds = (retry_after.as_millis() as u64).div_ceil(1000).max(1);
+	let response = Response::builder()
+		.status(StatusCode::SERVICE_UNAVAILABLE)
+		.header(RETRY_AFTER, seconds);
+
+	if let Some(file) = &config.fallback {
+		let path = Path::new(&server.root).join(file);
+		match tokio::fs::read(&path).await {
+			Ok(content) => {
+				let mime = from_path(&path).first_or_octet_stream();
+				return response
+					.header(CONTENT_TYPE, mime.as_ref())
+					.body(content.into())
+					.unwrap();
+			}
+			Err(e) => log::error!("failed to read fallback {}: {}", path.display(), e),
+		}
+	}
+	response
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body("Service Unavailable".into())
+		.unwrap()
+}
+
+impl Inner {
+	fn open(&mut self, now: Instant) {
+		self.state = CircuitState::Open;
+		self.since = Some(now);
+		self.buckets.clear();
+	}
+
+	fn open_time_left(&self, config: &CircuitBreaker) -> Duration {
+		self.since
+			.map(|since| config.open_duration().saturating_sub(since.elapsed()))
+			.unwrap_or_default()
+	}
+
+	fn second(&mut self, now: Instant) -> u64 {
+		(now - *self.epoch.get_or_insert(now)).as_secs()
+	}
+
+	fn count(&mut self, now: Instant, config: &CircuitBreaker, failed: bool) {
+		let window = config.window.max(1) as usize;
+		let second = self.second(now);
+		if self.buckets.len() != window {
+			self.buckets = vec![(0, 0, 0); window];
+		}
+		let bucket = &mut self.buckets[second as usize % window];
+		if bucket.0 != second {
+			*bucket = (second, 0, 0);
+		}
+		bucket.1 += 1;
+		bucket.2 += failed as u32;
+	}
+
+	fn totals(&mut self, now: Instant, config: &CircuitBreaker) -> (u32, u32) {
+		let second = self.second(now);
+		self.buckets
+			.iter()
+			.filter(|(bucket, requests, _)| *requests > 0 && second - bucket < config.window)
+			.fold((0, 0), |(requests, failures), bucket| {
+				(requests + bucket.1, failures + bucket.2)
+			})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(json: &str) -> CircuitBreaker {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn opens_on_error_rate() {
+		let config = config(r#"{"min_requests": 4, "error_rate": 50, "open_duration": 60}"#);
+		let breaker = Circuit::default();
+		let fast = Duration::from_millis(1);
+
+		breaker.record("a", &config, true, fast);
+		breaker.record("a", &config, true, fast);
+		breaker.record("a", &config, false, fast);
+		assert_eq!(breaker.state(), CircuitState::Closed);
+		breaker.record("a", &config, false, fast);
+		assert_eq!(breaker.state(), CircuitState::Open);
+		assert!(!breaker.allows_traffic(&config));
+		let retry_after = breaker.admit("a", &config).unwrap_err();
+		assert!(retry_after > Duration::from_secs(59));
+	}
+
+	#[test]
+	fn slow_calls_count_as_failures() {
+		let config = config(r#"{"min_requests": 2, "error_rate": 100, "slow_call": 100}"#);
+		let breaker = Circuit::default();
+		breaker.record("a", &config, false, Duration::from_millis(150));
+		breaker.record("a", &config, false, Duration::from_millis(200));
+		assert_eq!(breaker.state(), CircuitState::Open);
+	}
+
+	#[test]
+	fn half_open_trials_close_or_reopen() {
+		let config = config(
+			r#"{"min_requests": 1, "error_rate": 100, "open_duration": 0, "half_open_requests": 2}"#,
+		);
+		let breaker = Circuit::default();
+		let fast = Duration::from_millis(1);
+
+		breaker.record("a", &config, true, fast);
+		assert_eq!(breaker.state(), CircuitState::Open);
+		assert!(breaker.allows_traffic(&config));
+		assert!(breaker.admit("a", &config).is_ok());
+		assert_eq!(breaker.state(), CircuitState::HalfOpen);
+		breaker.record("a", &config, true, fast);
+		assert_eq!(breaker.state(), CircuitState::Open);
+
+		assert!(breaker.admit("a", &config).is_ok());
+		assert!(breaker.admit("a", &config).is_ok());
+		assert!(breaker.admit("a", &config).is_err());
+		breaker.record("a", &config, false, fast);
+		breaker.record("a", &config, false, fast);
+		assert_eq!(breaker.state(), CircuitState::Closed);
+	}
+}
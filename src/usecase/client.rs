@@ -0,0 +1,216 @@
+use std::{
+	collections::{hash_map::Entry, HashMap},
+	sync::Arc,
+	time::Duration,
+};
+
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::HttpsConnector;
+
+use super::timeout::UpstreamTimeouts;
+use crate::{
+	config::{Configuration, HttpVersion, Proxy, Timeouts, UpstreamTls},
+	prelude::*,
+	tls,
+};
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// Connection settings of an upstream client. Proxies with equal settings share one pool.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ClientSettings {
+	pub pool_max_idle_per_host: Option<usize>,
+	pub pool_idle_timeout: Option<u64>,
+	pub http_version: HttpVersion,
+	pub connect_timeout: Option<Duration>,
+	pub tls: Option<UpstreamTls>,
+}
+
+impl ClientSettings {
+	/// Settings of a proxy, `defaults` being the global timeouts.
+	pub fn from_proxy(proxy: &Proxy, defaults: &Timeouts) -> Self {
+		ClientSettings {
+			pool_max_idle_per_host: proxy.pool_max_idle_per_host,
+			pool_idle_timeout: proxy.pool_idle_timeout,
+			http_version: proxy.http_version,
+			connect_timeout: UpstreamTimeouts::resolve(&proxy.timeouts, defaults).connect,
+			tls: proxy.upstream_tls.clone(),
+		}
+	}
+
+	/// Creates the client, reading the `upstream_tls` files.
+	fn build(&self) -> Result<HttpsClient> {
+		let mut http = HttpConnector::new();
+		http.enforce_http(false);
+		http.set_connect_timeout(self.connect_timeout);
+		let https = match &self.tls {
+			None => hyper_rustls::HttpsConnectorBuilder::new().with_native_roots(),
+			Some(tls) => {
+				if tls.dangerous_skip_verify {
+					log::warn!("upstream certificates are not verified (dangerous_skip_verify)");
+				}
+				hyper_rustls::HttpsConnectorBuilder::new()
+					.with_tls_config(tls::upstream_config(tls)?)
+			}
+		}
+		.https_or_http();
+		let https = match self.tls.as_ref().and_then(|tls| tls.server_name.clone()) {
+			Some(server_name) => https.with_server_name(server_name),
+			None => https,
+		};
+		let https = match self.http_version {
+			HttpVersion::Http1 => https.enable_http1().wrap_connector(http),
+			HttpVersion::Http2 => https.enable_http2().wrap_connector(http),
+			HttpVersion::Auto => https.enable_all_versions().wrap_connector(http),
+		};
+
+		let mut builder = Client::builder();
+		if let Some(max_idle) = self.pool_max_idle_per_host {
+			builder.pool_max_idle_per_host(max_idle);
+		}
+		if let Some(idle_timeout) = self.pool_idle_timeout {
+			builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+		}
+		builder.http2_only(self.http_version == HttpVersion::Http2);
+		Ok(builder.build(https))
+	}
+}
+
+/// Pooled upstream clients, created once at startup and shared by every request.
+pub struct Clients {
+	// shared with the `Clients` of the next reload when the settings stay the same
+	clients: HashMap<ClientSettings, Arc<HttpsClient>>,
+	timeouts: Timeouts,
+}
+
+impl Clients {
+	/// Creates a client for every distinct proxy setting. Clients of `previous` with the same
+	/// settings are reused so a configuration reload keeps the idle connections.
+	pub fn from_config(config: &Configuration, previous: Option<&Clients>) -> Result<Self> {
+		let mut clients = HashMap::new();
+		let mut add = |settings: ClientSettings| -> Result<()> {
+			if let Entry::Vacant(entry) = clients.entry(settings) {
+				let client = match previous.and_then(|previous| previous.clients.get(entry.key())) {
+					Some(client) => Arc::clone(client),
+					None => Arc::new(entry.key().build()?),
+				};
+				entry.insert(client);
+			}
+			Ok(())
+		};
+
+		// used by routes created on the fly, such as the fingerprintjs loader
+		add(ClientSettings::from_proxy(
+			&Proxy::default(),
+			&config.timeouts,
+		))?;
+		for server in &config.http.servers {
+			for proxy in &server.proxies {
+				add(ClientSettings::from_proxy(proxy, &config.timeouts)).map_err(|e| {
+					Error::Generic(f!(
+						"server {:?}: upstream_tls of {:?}: {}",
+						server.name,
+						proxy.proxy_path,
+						e
+					))
+				})?;
+			}
+		}
+		Ok(Clients {
+			clients,
+			timeouts: config.timeouts,
+		})
+	}
+
+	/// Returns the shared client for a proxy; clones share the same connection pool.
+	pub fn for_proxy(&self, proxy: &Proxy) -> HttpsClient {
+		let settings = ClientSettings::from_proxy(proxy, &self.timeouts);
+		match self.clients.get(&settings) {
+			Some(client) => HttpsClient::clone(client),
+			None => {
+				log::warn!("no pooled client for {:?}, creating one", settings);
+				settings.build().unwrap_or_else(|e| {
+					log::error!("{}, using the default client settings", e);
+					let default = ClientSettings::from_proxy(&Proxy::default(), &self.timeouts);
+					HttpsClient::clone(&self.clients[&default])
+				})
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(proxies: &str) -> Configuration {
+		serde_json::from_str(&format!(
+			r#"{{"timeouts": {{"connect": 5}}, "http": {{"servers": [{{"root": "static",
+			"name": "a", "listen": "3400", "proxies": [{proxies}]}}]}}}}"#
+		))
+		.unwrap()
+	}
+
+	#[test]
+	fn resolves_settings() {
+		let config = config(
+			r#"{"proxy_pass": "http://a", "proxy_path": "/a", "retain_path": true},
+			{"proxy_pass": "http://b", "proxy_path": "/b", "retain_path": true,
+			 "timeouts": {"connect": 0}, "http_version": "auto", "pool_idle_timeout": 30}"#,
+		);
+		let proxies = &config.http.servers[0].proxies;
+		assert_eq!(
+			ClientSettings::from_proxy(&proxies[0], &config.timeouts),
+			ClientSettings {
+				connect_timeout: Some(Duration::from_secs(5)),
+				..Default::default()
+			}
+		);
+		assert_eq!(
+			ClientSettings::from_proxy(&proxies[1], &config.timeouts),
+			ClientSettings {
+				pool_idle_timeout: Some(30),
+				http_version: HttpVersion::Auto,
+				..Default::default()
+			}
+		);
+	}
+
+	#[test]
+	fn shares_clients_with_equal_settings() {
+		let config = config(
+			r#"{"proxy_pass": "http://a", "proxy_path": "/a", "retain_path": true},
+			{"proxy_pass": "http://b", "proxy_path": "/b", "retain_path": true,
+			 "timeouts": {"connect": 5}},
+			{"proxy_pass": "http://c", "proxy_path": "/c", "retain_path": true,
+			 "pool_max_idle_per_host": 4},
+			{"proxy_pass": "http://d", "proxy_path": "/d", "retain_path": true,
+			 "http_version": "http2"}"#,
+		);
+		// the default settings of `/a` and `/b` are also those of the routes created on the fly
+		let clients = Clients::from_config(&config, None).unwrap();
+		assert_eq!(clients.clients.len(), 3);
+
+		// a reload keeps the clients, and their idle connections, of unchanged settings only
+		let mut changed = config.clone();
+		changed.http.servers[0].proxies[3].pool_idle_timeout = Some(30);
+		let reloaded = Clients::from_config(&changed, Some(&clients)).unwrap();
+		assert_eq!(reloaded.clients.len(), 3);
+		let [a, _, c, d] = &config.http.servers[0].proxies[..] else {
+			panic!("four proxies");
+		};
+		for proxy in [a, c] {
+			let settings = ClientSettings::from_proxy(proxy, &config.timeouts);
+			assert!(Arc::ptr_eq(
+				&clients.clients[&settings],
+				&reloaded.clients[&settings]
+			));
+		}
+		let settings = ClientSettings::from_proxy(d, &config.timeouts);
+		assert!(!reloaded.clients.contains_key(&settings));
+		let settings =
+			ClientSettings::from_proxy(&changed.http.servers[0].proxies[3], &changed.timeouts);
+		assert!(!clients.clients.contains_key(&settings));
+		assert!(reloaded.clients.contains_key(&settings));
+	}
+}
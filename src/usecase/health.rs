@@ -0,0 +1,171 @@
+use std::{
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::config::{HealthCheck, PassiveHealthCheck};
+
+/// Health of one upstream, fed by active probes and by the outcome of proxied requests.
+#[derive(Debug, Default)]
+pub struct Health {
+	inner: Mutex<HealthInner>,
+}
+
+#[derive(Debug, Default)]
+struct HealthInner {
+	// set by active probes, cleared once enough probes succeed again
+	down: bool,
+	probe_successes: u32,
+	probe_failures: u32,
+	// consecutive failed requests, reset by any successful one
+	request_failures: u32,
+	ejected_until: Option<Instant>,
+}
+
+/// Health of an upstream as shown by the admin endpoint.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+	pub available: bool,
+	pub down: bool,
+	// seconds left before an ejected upstream gets traffic again
+	pub ejected_for: Option<u64>,
+	pub consecutive_failures: u32,
+}
+
+impl Health {
+	/// Whether the upstream should get traffic.
+	pub fn is_available(&self) -> bool {
+		let inner = self.inner.lock().unwrap();
+		!inner.down && !inner.is_ejected(Instant::now())
+	}
+
+	/// Records an active probe, marking the upstream down or up once a threshold is reached.
+	pub fn record_probe(&self, url: &str, success: bool, check: &HealthCheck) {
+		let mut inner = self.inner.lock().unwrap();
+		if success {
+			inner.probe_failures = 0;
+			inner.probe_successes += 1;
+			if inner.down && inner.probe_successes >= check.healthy_threshold {
+				inner.down = false;
+				inner.request_failures = 0;
+				inner.ejected_until = None;
+				log::info!(
+					"upstream {} is healthy after {} successful probes",
+					url,
+					inner.probe_successes
+				);
+			}
+		} else {
+			inner.probe_successes = 0;
+			inner.probe_failures += 1;
+			if !inner.down && inner.probe_failures >= check.unhealthy_threshold {
+				inner.down = true;
+				log::warn!(
+					"upstream {} is unhealthy after {} failed probes",
+					url,
+					inner.probe_failures
+				);
+			}
+		}
+	}
+
+	/// Records the outcome of a proxied request, a failure being a connection error or a 5xx.
+	///
+	/// With `passive` set, the upstream is ejected after too many consecutive failures.
+	pub fn record_request(&self, url: &str, failed: bool, passive: Option<&PassiveHealthCheck>) {
+		let mut inner = self.inner.lock().unwrap();
+		if !failed {
+			inner.request_failures = 0;
+			return;
+		}
+		inner.request_failures += 1;
+
+		let Some(passive) = passive else {
+			return;
+		};
+		let now = Instant::now();
+		if inner.request_failures >= passive.max_failures && !inner.is_ejected(now) {
+			inner.ejected_until = Some(now + Duration::from_secs(passive.ejection_time));
+			log::warn!(
+				"upstream {} ejected for {}s after {} consecutive failures",
+				url,
+				passive.ejection_time,
+				inner.request_failures
+			);
+			inner.request_failures = 0;
+		}
+	}
+
+	pub fn report(&self) -> HealthReport {
+		let inner = self.inner.lock().unwrap();
+		let now = Instant::now();
+		let ejected_for = inner
+			.ejected_until
+			.filter(|until| *until > now)
+			.map(|until| (until - now).as_secs());
+		HealthReport {
+			available: !inner.down && ejected_for.is_none(),
+			down: inner.down,
+			ejected_for,
+			consecutive_failures: inner.request_failures,
+		}
+	}
+}
+
+impl HealthInner {
+	fn is_ejected(&self, now: Instant) -> bool {
+		self.ejected_until.is_some_and(|until| until > now)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn check() -> HealthCheck {
+		serde_json::from_str(r#"{"healthy_threshold": 2, "unhealthy_threshold": 2}"#).unwrap()
+	}
+
+	#[test]
+	fn probes_mark_upstreams_down_and_up() {
+		let health = Health::default();
+		let check = check();
+
+		health.record_probe("http://a", false, &check);
+		assert!(health.is_available());
+		health.record_probe("http://a", false, &check);
+		assert!(!health.is_available());
+
+		health.record_probe("http://a", true, &check);
+		assert!(!health.is_available());
+		health.record_probe("http://a", true, &check);
+		assert!(health.is_available());
+	}
+
+	#[test]
+	fn consecutive_failures_eject_upstreams() {
+		let health = Health::default();
+		let passive = PassiveHealthCheck {
+			max_failures: 2,
+			ejection_time: 30,
+		};
+
+		health.record_request("http://a", true, Some(&passive));
+		health.record_request("http://a", false, Some(&passive));
+		health.record_request("http://a", true, Some(&passive));
+		assert!(health.is_available());
+		health.record_request("http://a", true, Some(&passive));
+		assert!(!health.is_available());
+		assert!(health.report().ejected_for.is_some());
+
+		// without passive checks failures are only counted
+		let health = Health::default();
+		for _ in 0..10 {
+			health.record_request("http://a", true, None);
+		}
+		assert!(health.is_available());
+		assert_eq!(health.report().consecutive_failures, 10);
+	}
+}
@@ -0,0 +1,294 @@
+use std::{
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use reqwest::{
+	header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+	Client, StatusCode,
+};
+use tokio::{fs, time::Instant};
+
+use crate::{
+	config::{IpListFormat, IpListSource},
+	utils::ip_list::IpList,
+};
+
+// Longest wait between two attempts of a failing source
+const MAX_BACKOFF: Duration = Duration::from_secs(900);
+// Time allowed to download a list
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Refresh state of one list source.
+#[derive(Debug, Default)]
+pub struct SourceState {
+	// validators of the last accepted response, so unchanged lists are not downloaded again
+	etag: Option<String>,
+	last_modified: Option<String>,
+	// modification time of the last accepted file
+	modified: Option<SystemTime>,
+	failures: u32,
+	next: Option<Instant>,
+}
+
+/// A list that passed the checks, with the text it was parsed from.
+pub struct Fetched {
+	pub list: IpList,
+	pub body: String,
+}
+
+impl SourceState {
+	pub fn is_due(&self, now: Instant) -> bool {
+		self.next.is_none_or(|next| next <= now)
+	}
+
+	/// Refreshes the source on the next tick, even while it is backing off.
+	pub fn make_due(&mut self) {
+		self.next = None;
+	}
+
+	/// Fetches the source, `None` when it did not change since the last accepted version.
+	pub async fn fetch(
+		&mut self,
+		client: &Client,
+		source: &IpListSource,
+	) -> Result<Option<Fetched>, String> {
+		match (&source.url, &source.file) {
+			(Some(url), _) => self.fetch_url(client, url, source).await,
+			(None, Some(file)) => self.read_file(file, source).await,
+			(None, None) => Err("no url or file".into()),
+		}
+	}
+
+	async fn fetch_url(
+		&mut self,
+		client: &Client,
+		url: &str,
+		source: &IpListSource,
+	) -> Result<Option<Fetched>, String> {
+		let mut request = client.get(url).timeout(FETCH_TIMEOUT);
+		if let Some(etag) = &self.etag {
+			request = request.header(IF_NONE_MATCH, etag);
+		}
+		if let Some(last_modified) = &self.last_modified {
+			request = request.header(IF_MODIFIED_SINCE, last_modified);
+		}
+		let response = request.send().await.map_err(|e| e.to_string())?;
+		let status = response.status();
+		if status == StatusCode::NOT_MODIFIED {
+			return Ok(None);
+		}
+		if !status.is_success() {
+			return Err(format!("status {}", status));
+		}
+
+		let header = |name| {
+			response
+				.headers()
+				.get(name)
+				.and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+				.map(str::to_string)
+		};
+		let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+		let body = response.text().await.map_err(|e| e.to_string())?;
+		let list = parse(&body, source)?;
+		self.etag = etag;
+		self.last_modified = last_modified;
+		Ok(Some(Fetched { list, body }))
+	}
+
+	async fn read_file(
+		&mut self,
+		path: &str,
+		source: &IpListSource,
+	) -> Result<Option<Fetched>, String> {
+		let modified = fs::metadata(path)
+			.await
+			.and_then(|meta| meta.modified())
+			.map_err(|e| format!("{}: {}", path, e))?;
+		if self.modified == Some(modified) {
+			return Ok(None);
+		}
+		let body = fs::read_to_string(path)
+			.await
+			.map_err(|e| format!("{}: {}", path, e))?;
+		let list = parse(&body, source)?;
+		self.modified = Some(modified);
+		Ok(Some(Fetched { list, body }))
+	}
+
+	/// Plans the next attempt: after `interval` on success, backing off exponentially from there
+	/// while the source keeps failing. Returns the delay.
+	pub fn schedule(&mut self, succeeded: bool, interval: Duration) -> Duration {
+		self.failures = if succeeded { 0 } else { self.failures + 1 };
+		let delay = interval
+			.saturating_mul(1 << self.failures.min(16))
+			.min(MAX_BACKOFF.max(interval));
+		self.next = Some(Instant::now() + delay);
+		delay
+	}
+}
+
+/// Parses a list in its `format`, rejecting it when it has invalid entries or fewer than
+/// `min_entries` ones, so an error page or an empty response never replaces a good list.
+pub fn parse(body: &str, source: &IpListSource) -> Result<IpList, String> {
+	let (list, invalid) = match source.format {
+		IpListFormat::Lines => IpList::parse(body),
+		IpListFormat::Json => {
+			let entries: Vec<String> =
+				serde_json::from_str(body).map_err(|e| format!("not a JSON array: {}", e))?;
+			let mut list = IpList::default();
+			let mut invalid = Vec::new();
+			for entry in entries {
+				if list.add(entry.trim()).is_err() {
+					invalid.push(entry);
+				}
+			}
+			(list, invalid)
+		}
+	};
+	if let Some(entry) = invalid.first() {
+		return Err(format!(
+			"{} invalid entries, such as {:?}",
+			invalid.len(),
+			entry
+		));
+	}
+	let entries = list.allow.len() + list.deny.len();
+	if entries < source.min_entries {
+		return Err(format!(
+			"{} entries, fewer than min_entries {}",
+			entries, source.min_entries
+		));
+	}
+	Ok(list)
+}
+
+fn cache_path(dir: &str, name: &str) -> PathBuf {
+	Path::new(dir).join(format!("{}.list", name))
+}
+
+/// Writes the last accepted version of a list to the `ip_list_cache` directory.
+pub async fn persist(dir: &str, name: &str, body: &str) {
+	let path = cache_path(dir, name);
+	let temporary = path.with_extension("list.tmp");
+	// written aside then renamed, so a crash never leaves a truncated list
+	let result = async {
+		fs::create_dir_all(dir).await?;
+		fs::write(&temporary, body).await?;
+		fs::rename(&temporary, &path).await
+	};
+	if let Err(e) = result.await {
+		log::error!(
+			"failed to save ip list {} to {}: {}",
+			name,
+			path.display(),
+			e
+		);
+	}
+}
+
+/// Reads the version of a list saved by a previous run, if it still passes the checks.
+pub async fn load_cached(dir: &str, name: &str, source: &IpListSource) -> Option<IpList> {
+	let path = cache_path(dir, name);
+	let body = fs::read_to_string(&path).await.ok()?;
+	match parse(&body, source) {
+		Ok(list) => Some(list),
+		Err(e) => {
+			log::warn!("ignoring saved ip list {}: {}", path.display(), e);
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn source(json: &str) -> IpListSource {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn rejects_suspicious_lists() {
+		let lines = source(r#"{"url": "http://lists.test/ips", "min_entries": 2}"#);
+		assert!(parse("10.0.0.0/8\n!10.6.0.0/16\n", &lines).is_ok());
+		assert!(parse("10.0.0.0/8\n", &lines).is_err());
+		assert!(parse("", &lines).is_err());
+		assert!(parse(
+			"<html>Service Unavailable</html>\n10.0.0.0/8\n10.1.0.0/16",
+			&lines
+		)
+		.is_err());
+
+		let json = source(r#"{"file": "ips.json", "format": "json"}"#);
+		let list = parse(r#"["192.0.2.0/24", "!192.0.2.1"]"#, &json).unwrap();
+		assert_eq!((list.allow.len(), list.deny.len()), (1, 1));
+		assert!(parse("192.0.2.0/24", &json).is_err());
+		assert!(parse("[]", &json).is_err());
+	}
+
+	#[test]
+	fn backs_off_while_failing() {
+		let mut state = SourceState::default();
+		let interval = Duration::from_secs(30);
+		assert_eq!(state.schedule(true, interval), interval);
+		assert_eq!(state.schedule(false, interval), interval * 2);
+		assert_eq!(state.schedule(false, interval), interval * 4);
+		for _ in 0..10 {
+			state.schedule(false, interval);
+		}
+		assert_eq!(state.schedule(false, interval), MAX_BACKOFF);
+		assert_eq!(state.schedule(true, interval), interval);
+	}
+
+	// Directory of its own for a test, removed when dropped
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(test: &str) -> Self {
+			let dir = std::env::temp_dir().join(format!(
+				"rust-reverse-proxy-{}-{}",
+				test,
+				std::process::id()
+			));
+			std::fs::create_dir_all(&dir).unwrap();
+			TempDir(dir)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[tokio::test]
+	async fn keeps_lists_across_restarts() {
+		let temp = TempDir::new("ip-list-cache");
+		let dir = temp.0.join("cache");
+		let dir = dir.to_string_lossy();
+		let lines = source(r#"{"url": "http://lists.test/ips"}"#);
+		persist(&dir, "partners", "192.0.2.0/24\n").await;
+		let list = load_cached(&dir, "partners", &lines).await.unwrap();
+		assert_eq!(list.allow.len(), 1);
+		assert!(load_cached(&dir, "missing", &lines).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn reads_changed_files_only() {
+		let temp = TempDir::new("ip-list-file");
+		let path = temp.0.join("ips.txt");
+		std::fs::write(&path, "192.0.2.0/24\n198.51.100.0/24\n").unwrap();
+		let file = source(&format!(r#"{{"file": {:?}}}"#, path.to_string_lossy()));
+		let client = Client::new();
+
+		let mut state = SourceState::default();
+		let fetched = state.fetch(&client, &file).await.unwrap().unwrap();
+		assert_eq!(fetched.list.allow.len(), 2);
+		assert!(state.fetch(&client, &file).await.unwrap().is_none());
+
+		std::fs::remove_file(&path).unwrap();
+		assert!(state.fetch(&client, &file).await.is_err());
+	}
+}
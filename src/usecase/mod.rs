@@ -1,3 +1,17 @@
+pub mod access;
+pub mod balancer;
+pub mod circuit_breaker;
+pub mod client;
+pub mod health;
+pub mod ip_source;
 pub mod proxy;
+pub mod redirect;
+pub mod retry;
+pub mod rewrite;
+pub mod router;
 pub mod static_file;
+// debugging helper nothing uses at the moment
+#[allow(dead_code, clippy::needless_return)]
 pub mod stream;
+pub mod timeout;
+pub mod upstream_error;
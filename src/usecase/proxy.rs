@@ -1,184 +1,317 @@
 use crate::{
-	config::{Configuration, Proxy},
-	utils::{compression, control_headers, cookie, fingerprintjs, security_headers},
+	access_log::{self, RequestInfo, UpstreamTiming},
+	config::{HashKey, LoadBalancing, Proxy, Server, UnknownHost},
+	metrics::{self, RouteLabels},
+	state::{AppState, Snapshot},
+	tls::{ClientCert, TlsSession},
+	trace::{self, SpanKind},
+	utils::{
+		client_ip, compression, control_headers, cookie, fingerprintjs, host, security_headers,
+	},
 };
 
 use hyper::{
-	client::HttpConnector,
 	http::{HeaderName, HeaderValue},
-	Body, Client, HeaderMap, Method, Request, Response, StatusCode,
+	Body, HeaderMap, Method, Request, Response, StatusCode,
 };
-use hyper_rustls::HttpsConnector;
 
 use std::{
-	collections::HashSet,
-	net::IpAddr,
-	sync::{Arc, Mutex},
+	collections::HashMap,
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+	time::Duration,
+};
+use tokio::time::Instant;
+
+use super::{
+	access::{self, AccessRule},
+	balancer, circuit_breaker,
+	client::HttpsClient,
+	retry::{self, RetryBody},
+	rewrite,
+	router::{self, Route, RouteMatch},
+	static_file::compressed_static_files,
+	timeout, upstream_error,
 };
-
-use super::static_file::compressed_static_files;
 
 const IGNORE_CACHE: [&str; 3] = ["gzip", "deflate", "br"];
 
+/// Client connection a request came in on.
+#[derive(Debug)]
+pub struct Connection {
+	pub listener: SocketAddr,
+	pub remote: SocketAddr,
+	pub tls: Option<TlsSession>,
+}
+
 pub async fn mirror(
 	req: Request<Body>,
-	whitelisted_ips: Arc<Mutex<HashSet<IpAddr>>>,
-	config: Arc<Configuration>,
+	state: Arc<AppState>,
+	conn: Arc<Connection>,
 ) -> Result<Response<Body>, hyper::Error> {
-	// Create a new HTTP client to send requests
-
-	let https = hyper_rustls::HttpsConnectorBuilder::new()
-		.with_native_roots()
-		.https_or_http()
-		.enable_http1()
-		.build();
+	// Keep using this snapshot even if the configuration is reloaded mid-request
+	let snapshot = state.snapshot();
+	let client_ip = client_ip::resolve(
+		req.headers(),
+		conn.remote,
+		&snapshot.trusted_proxies,
+		snapshot.config.client_ip_header,
+	);
+	let mut request = RequestInfo::new(&req, client_ip);
+	let span = snapshot
+		.tracing
+		.clone()
+		.map(|settings| trace::start_request(&req, client_ip, settings, &state.tracer));
+	let req = request.count_body(req);
+	let routed = route_request(req, &state, &snapshot, &conn, client_ip, &mut request.route);
+	let res = match &span {
+		// spans started while routing, compressing or reading files are its children
+		Some(span) => span.scope(routed).await?,
+		None => routed.await?,
+	};
+	request.span = span;
+	Ok(access_log::finish_response(
+		res,
+		request,
+		snapshot.access_log.clone(),
+		&state.access_log,
+	))
+}
 
-	let client = Client::builder().build::<_, hyper::Body>(https);
+async fn route_request(
+	req: Request<Body>,
+	state: &AppState,
+	snapshot: &Snapshot,
+	conn: &Connection,
+	client_ip: IpAddr,
+	labels: &mut RouteLabels,
+) -> Result<Response<Body>, hyper::Error> {
+	let listener = conn.listener;
+	let config = &snapshot.config;
+	let clients = &snapshot.clients;
+
+	// Serve the request only if the IP passes the access policies of every level
+	let forbidden = |req: &Request<Body>, levels: &[Option<&AccessRule>]| {
+		let ip_lists = state.ip_lists.lock().unwrap();
+		let res = access::check(client_ip, levels, &ip_lists)?;
+		log::info!("{} {} forbidden for {}", req.method(), req.uri(), client_ip);
+		Some(res)
+	};
 
-	let default_ips = get_ips_from_string(&config.default_ip_whitelist);
-	if default_ips.len() > 0 {
-		whitelisted_ips.lock().unwrap().extend(default_ips);
-	}
+	let mut matching = trace::span("route", SpanKind::Internal);
+
+	// Pick the server block matching the requested host among those bound to this listener
+	let servers: Vec<&Server> = snapshot
+		.servers_for(&listener)
+		.iter()
+		.filter_map(|&index| config.http.servers.get(index))
+		.collect();
+	let host = host::request_host(&req);
+	let (index, (server, routes)) =
+		match router::select_server(&servers, host.as_deref(), config.http.unknown_host) {
+			Some(position) => {
+				let index = snapshot.servers_for(&listener)[position];
+				(index, snapshot.server(index))
+			}
+			None => {
+				log::debug!("no server block for host {:?}", host);
+				if let Some(res) = forbidden(&req, &[Some(&snapshot.access)]) {
+					return Ok(res);
+				}
+				return match config.http.unknown_host {
+					UnknownHost::Misdirected => Ok(Response::builder()
+						.status(StatusCode::MISDIRECTED_REQUEST)
+						.body("Misdirected Request".into())
+						.unwrap()),
+					_ => handle(req).await,
+				};
+			}
+		};
 
-	if whitelisted_ips.lock().unwrap().len() > 0 {
-		// If the IP is whitelisted, serve the request
-		let forwarded_ips = get_ips_from_x_forwarded_for(&req);
-		if !is_ip_in_whitelist(forwarded_ips, &whitelisted_ips.lock().unwrap()) {
-			// return 403 shows what ip come from like "Forbidded from ip :123.23124.512"
-			return Ok(Response::builder()
-				.status(StatusCode::FORBIDDEN)
-				.body("Forbidden".into())
-				.unwrap());
-		}
-	}
+	labels.server.clone_from(&server.name);
 
 	// Extract the path component from the incoming HTTP request's URI
 	let path = req.uri().path();
 	let method = req.method().clone();
 	let mut headers = req.headers().clone();
 
-	for server in &config.http.servers {
-		// check if the fp cookie exist;
-		let fp_cookie: Option<String> = match server.fingerprintjs {
-			Some(_) => cookie::extract_specific_cookie_from_headermap(&headers, "_fp_id"),
-			None => None,
-		};
-		// check the value of fp_cookie
-		match &fp_cookie {
-			Some(fp_cookie) => {
-				let cook = fingerprintjs::parse_cookie(&fp_cookie);
-				match cook {
-					Ok(cook) => {
-						// add addtional header to proxy
-						headers.insert(
-							"X-FP-Visitor",
-							HeaderValue::from_str(&cook.visitor_id).unwrap(),
-						);
-						headers.insert(
-							"X-FP-Request",
-							HeaderValue::from_str(&cook.request_id).unwrap(),
-						);
-					}
-					Err(_) => {}
-				}
-			}
-			None => {}
+	// Pick the proxy defined for the current server that matches the path, if any
+	let route = router::select_route(&server.proxies, routes, path);
+	if let Some(span) = &mut matching {
+		span.set("proxy.server", server.name.as_str());
+		if let Some(route) = &route {
+			span.set("http.route", route.proxy.proxy_path.as_str());
 		}
+	}
+	drop(matching);
+	let levels = [
+		Some(&snapshot.access),
+		snapshot.server_access[index].as_ref(),
+		route.as_ref().and_then(|route| route.route.access.as_ref()),
+	];
+	if let Some(res) = forbidden(&req, &levels) {
+		return Ok(res);
+	}
+	if let Some(route) = &route {
+		labels.route.clone_from(&route.proxy.proxy_path);
+	}
+	if method == Method::GET && config.metrics_path.as_deref() == Some(path) {
+		return Ok(metrics::response(state));
+	}
 
-		// Iterate over all proxies defined for the current server
-		for proxy in &server.proxies {
-			// Check if the request URI's path starts with the current proxy's path
-			if path.starts_with(&proxy.proxy_path) {
-				return proxy_request(req, client, proxy, &headers, &method).await;
-			}
+	// Client certificates are only trusted by the server block that verified them
+	let client_auth = server.tls.as_ref().and_then(|tls| tls.client_auth.as_ref());
+	let client_cert = conn
+		.tls
+		.as_ref()
+		.filter(|session| session.server == index)
+		.and_then(|session| session.client_cert.as_ref());
+	if let Some(client_auth) = client_auth {
+		if conn.tls.as_ref().map(|session| session.server) != Some(index) {
+			return Ok(Response::builder()
+				.status(StatusCode::MISDIRECTED_REQUEST)
+				.body("Misdirected Request".into())
+				.unwrap());
 		}
-
-		let mut scripts = Vec::new();
-		let mut onloadfunction = None;
-
-		if server.fingerprintjs.is_some() && fp_cookie.is_none() {
-			// if the path is not a proxy, serve the static files
-			let fingerprint_id = server.fingerprintjs.as_ref().unwrap();
-			if path.starts_with("/js/fp.js") {
-				// create new proxy for fingerprintjs
-				let proxy = Proxy {
-					proxy_pass: format!("https://fpjscdn.net/v3/{}", fingerprint_id),
-					proxy_path: "/js/fp.js".to_string(),
-					retain_path: true,
-					request_headers: None,
-				};
-				return proxy_request(req, client, &proxy, &headers, &method).await;
+		let values = client_cert.map(ClientCert::header_values);
+		for (position, name) in client_auth.headers().into_iter().enumerate() {
+			let Some(name) = name else { continue };
+			// never pass on values sent by the client
+			headers.remove(name);
+			let value = values
+				.as_ref()
+				.and_then(|values| HeaderValue::from_str(&values[position]).ok());
+			if let Some(value) = value {
+				headers.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), value);
 			}
+		}
+	}
 
-			scripts.push(fingerprintjs::FP_SCRIPT);
-			onloadfunction = Some("initFpCookie();".to_string());
+	// check if the fp cookie exist;
+	let fp_cookie: Option<String> = match server.fingerprintjs {
+		Some(_) => cookie::extract_specific_cookie_from_headermap(&headers, "_fp_id"),
+		None => None,
+	};
+	// check the value of fp_cookie
+	let mut visitor_id = None;
+	if let Some(fp_cookie) = &fp_cookie {
+		if let Ok(cook) = fingerprintjs::parse_cookie(fp_cookie) {
+			// add addtional header to proxy
+			headers.insert(
+				"X-FP-Visitor",
+				HeaderValue::from_str(&cook.visitor_id).unwrap(),
+			);
+			headers.insert(
+				"X-FP-Request",
+				HeaderValue::from_str(&cook.request_id).unwrap(),
+			);
+			visitor_id = Some(cook.visitor_id);
 		}
+	}
 
-		let compressed: Result<Response<Body>, hyper::Error> = compressed_static_files(
-			path,
-			&server.root,
-			&method,
+	if let Some(route) = route {
+		if route.proxy.require_client_cert && client_cert.is_none() {
+			return Ok(Response::builder()
+				.status(StatusCode::FORBIDDEN)
+				.body("Client certificate required".into())
+				.unwrap());
+		}
+		let client = clients.for_proxy(route.proxy);
+		let key = balance_key(&route, &req, client_ip, visitor_id.as_deref());
+		return proxy_request(
+			req,
+			client,
+			server,
+			&route,
 			&headers,
-			scripts,
-			onloadfunction.as_ref(),
+			&method,
+			key.as_deref(),
 		)
 		.await;
-		return compressed;
 	}
-	let result = handle(req).await;
 
-	return result;
+	let mut scripts = Vec::new();
+	let mut onloadfunction = None;
+
+	if let (Some(fingerprint_id), None) = (&server.fingerprintjs, &fp_cookie) {
+		// if the path is not a proxy, serve the static files
+		if let Some(rest) = path.strip_prefix("/js/fp.js") {
+			// create new proxy for fingerprintjs
+			let proxy = Proxy {
+				proxy_pass: format!("https://fpjscdn.net/v3/{}", fingerprint_id),
+				proxy_path: "/js/fp.js".to_string(),
+				retain_path: true,
+				..Default::default()
+			};
+			let compiled = match Route::compile(&proxy, &config.timeouts, &mut HashMap::new()) {
+				Ok(compiled) => compiled,
+				Err(e) => {
+					log::error!("failed to create the fingerprintjs route: {}", e);
+					return handle(req).await;
+				}
+			};
+			let route = RouteMatch {
+				proxy: &proxy,
+				route: &compiled,
+				rest: rest.to_string(),
+				captures: HashMap::new(),
+			};
+			labels.route.clone_from(&proxy.proxy_path);
+			let client = clients.for_proxy(&proxy);
+			return proxy_request(req, client, server, &route, &headers, &method, None).await;
+		}
+
+		scripts.push(fingerprintjs::FP_SCRIPT);
+		onloadfunction = Some("initFpCookie();".to_string());
+	}
+
+	compressed_static_files(
+		path,
+		&server.root,
+		&method,
+		&headers,
+		scripts,
+		onloadfunction.as_ref(),
+	)
+	.await
 }
 
 // Asynchronous function named 'handle'. It acts as a router for HTTP requests based on path
 async fn proxy_request(
 	req: Request<Body>,
-	client: Client<HttpsConnector<HttpConnector>>,
-	proxy: &Proxy,
+	client: HttpsClient,
+	server: &Server,
+	route: &RouteMatch<'_>,
 	header: &HeaderMap<HeaderValue>,
 	method: &Method,
+	balance_key: Option<&str>,
 ) -> Result<Response<Body>, hyper::Error> {
-	// Log the proxy
-	let full_url = &proxy.proxy_pass.clone();
-	let original_headers = req.headers().clone();
-	let query_params = req.uri().query().unwrap_or("");
+	let started = Instant::now();
+	let proxy = route.proxy;
+	let balancer = &route.route.balancer;
+	let (parts, body) = req.into_parts();
+	let query_params = parts.uri.query().unwrap_or("");
 
 	// add uri with query params
-	let path = req.uri().path();
+	let path = parts.uri.path();
 	let sec_path = path.to_string();
-	// add final path for replacement
-
-	let final_path = path.replace(&proxy.proxy_path, "");
-	let uri = match (proxy.retain_path, query_params.is_empty()) {
-		(true, true) => format!("{}{}", full_url, final_path),
-		(true, false) => format!("{}{}?{}", full_url, final_path, &query_params),
-		(false, true) => format!("{}{}", full_url, &path),
-		(false, false) => format!("{}{}?{}", full_url, &path, &query_params),
-	};
-
-	let request_result = Request::builder()
-		.method(req.method())
-		.uri(&uri)
-		.body(req.into_body());
 
-	let mut request = match request_result {
-		Ok(req) => req,
-		Err(e) => {
-			// handle the error here, perhaps logging it and returning a response indicating the error
-			println!("Failed to construct the request: {}", e);
-			return Ok(Response::builder()
-				.status(StatusCode::INTERNAL_SERVER_ERROR)
-				.body("Internal Server Error".into())
-				.unwrap());
-		}
+	// retain_path forwards the path without the matched part, then rewrite rules apply
+	let final_path = if proxy.retain_path { &route.rest } else { path };
+	let (final_path, query_params) =
+		rewrite::rewrite_target(final_path, query_params, route.route.rewrites());
+	let query_params = rewrite::rewrite_query(&query_params, &proxy.query, &route.captures);
+	let path_and_query = if query_params.is_empty() {
+		final_path
+	} else {
+		format!("{}?{}", final_path, query_params)
 	};
 
-	// Get a mutable reference to the request's headers
-	let headers: &mut HeaderMap<HeaderValue> = request.headers_mut();
+	let mut forward_headers = HeaderMap::new();
 	let mut ignore_cache = true;
 
-	// Copy all the headers from the original request
-	for (name, value) in original_headers {
+	// Copy all the headers from the original request, as amended by `mirror`
+	for (name, value) in header.clone() {
 		// Convert the key to a HeaderName and the value to a HeaderValue
 		if let Some(header_name) = name {
 			// Skip the host header
@@ -190,7 +323,7 @@ async fn proxy_request(
 				ignore_cache = false;
 			}
 
-			headers.insert(header_name, value);
+			forward_headers.insert(header_name, value);
 		}
 	}
 
@@ -202,18 +335,197 @@ async fn proxy_request(
 			if let Some(s_headers) = &request_header.headers {
 				// Iterate over each key-value pair in the header
 				for (key, value) in s_headers {
-					// Convert the key to a HeaderName and the value to a HeaderValue
-					let header_name = HeaderName::from_bytes(key.as_bytes()).unwrap();
-					let header_value: HeaderValue = HeaderValue::from_str(value).unwrap();
+					// Convert the key to a HeaderName and the value to a HeaderValue, both checked
+					// by `Configuration::validate` before the captures are expanded
+					let Ok(header_name) = HeaderName::from_bytes(key.as_bytes()) else {
+						log::warn!("skipping invalid request header name {:?}", key);
+						continue;
+					};
+					// regex routes can reference their named groups, e.g. `$tenant`
+					let value = router::expand_captures(value, &route.captures);
+					let Ok(header_value) = HeaderValue::from_str(&value) else {
+						log::warn!(
+							"skipping request header {} with invalid value {:?}",
+							key,
+							value
+						);
+						continue;
+					};
 
 					// Add the custom header to the request
-					headers.insert(header_name, header_value);
+					forward_headers.insert(header_name, header_value);
 				}
 			}
 		}
 	}
 
-	let res = client.request(request).await?;
+	// the body is kept in memory when the request may be sent again
+	let retry = proxy
+		.retry
+		.as_ref()
+		.filter(|retry| retry::allows(retry, &parts.method));
+	let mut body = match retry {
+		Some(retry) => RetryBody::buffer(body, &parts.headers, retry.max_body_size).await?,
+		None => RetryBody::Streaming(body),
+	};
+	if retry.is_some() && !body.is_replayable() {
+		log::debug!(
+			"request body for {} is over max_body_size, not retrying",
+			proxy.proxy_path
+		);
+	}
+	let retry = retry.filter(|_| body.is_replayable());
+	let attempts = retry.map_or(1, |retry| retry.attempts.max(1));
+
+	let passive = proxy.passive_health_check.as_ref();
+	let timeouts = &route.route.timeouts;
+	let mut tried = Vec::new();
+	// upstreams found with an open circuit, and the soonest one of them closes
+	let mut open = Vec::new();
+	let mut retry_after: Option<Duration> = None;
+	let mut attempt = 0;
+	let (mut res, active, context, timing) = loop {
+		// another upstream when there is one, the same one otherwise, never one with an open circuit
+		let index = balancer
+			.pick(balance_key, &[&tried[..], &open[..]].concat())
+			.or_else(|| balancer.pick(balance_key, &open));
+		let Some(index) = index else {
+			if let (Some(breaker), Some(retry_after)) = (balancer.circuit_breaker(), retry_after) {
+				log::warn!("503 for {}: every circuit open", proxy.proxy_path);
+				return Ok(circuit_breaker::open_response(breaker, retry_after, server).await);
+			}
+			log::error!("no upstream available for {}", proxy.proxy_path);
+			return Ok(Response::builder()
+				.status(StatusCode::SERVICE_UNAVAILABLE)
+				.body("Service Unavailable".into())
+				.unwrap());
+		};
+		let target = &balancer.targets()[index];
+		let context = format!(
+			"server {:?} route {} upstream {}",
+			server.name, proxy.proxy_path, target.url
+		);
+		// an open circuit skips the upstream without using up an attempt
+		if let Some(breaker) = balancer.circuit_breaker() {
+			if let Err(left) = target.state.circuit.admit(&target.url, breaker) {
+				log::debug!("{}: circuit open, trying another upstream", context);
+				open.push(index);
+				retry_after = Some(retry_after.map_or(left, |soonest| soonest.min(left)));
+				continue;
+			}
+		}
+		attempt += 1;
+		let uri = format!("{}{}", target.url, path_and_query);
+
+		let request_result = Request::builder()
+			.method(&parts.method)
+			.uri(&uri)
+			.body(body.take());
+
+		let mut request = match request_result {
+			Ok(req) => req,
+			Err(e) => {
+				// handle the error here, perhaps logging it and returning a response indicating the error
+				println!("Failed to construct the request: {}", e);
+				return Ok(Response::builder()
+					.status(StatusCode::INTERNAL_SERVER_ERROR)
+					.body("Internal Server Error".into())
+					.unwrap());
+			}
+		};
+		*request.headers_mut() = forward_headers.clone();
+
+		let mut span = trace::span("upstream", SpanKind::Client);
+		if let Some(span) = &mut span {
+			span.set("http.request.method", parts.method.as_str());
+			span.set("url.full", uri.as_str());
+			if attempt > 1 {
+				span.set("http.request.resend_count", i64::from(attempt - 1));
+			}
+			// the upstream continues the trace from this span
+			span.inject(request.headers_mut());
+		}
+
+		let active = target.state.begin();
+		let attempt_started = Instant::now();
+		let outcome = timeout::response_within(client.request(request), timeouts, started).await;
+		let failed = outcome
+			.as_ref()
+			.map_or(true, |res| res.status().is_server_error());
+		target
+			.state
+			.health
+			.record_request(&target.url, failed, passive);
+		if let Some(breaker) = balancer.circuit_breaker() {
+			let latency = attempt_started.elapsed();
+			target
+				.state
+				.circuit
+				.record(&target.url, breaker, failed, latency);
+		}
+
+		let timing = UpstreamTiming {
+			url: target.url.clone(),
+			latency: attempt_started.elapsed(),
+		};
+		if let Some(span) = &mut span {
+			match &outcome {
+				Ok(res) => {
+					span.set(
+						"http.response.status_code",
+						i64::from(res.status().as_u16()),
+					);
+					if res.status().is_server_error() {
+						span.set_error(res.status().to_string());
+					}
+				}
+				Err(error) => span.set_error(error.to_string()),
+			}
+		}
+		drop(span);
+		let last = attempt >= attempts;
+		match outcome {
+			Ok(res) => match retry {
+				Some(retry) if !last && retry::retries_status(retry, res.status()) => {
+					log::warn!(
+						"{} from {}, retrying (attempt {} of {})",
+						res.status().as_u16(),
+						context,
+						attempt,
+						attempts
+					);
+				}
+				_ => break (res, active, context, timing),
+			},
+			Err(error) => match retry {
+				Some(retry) if !last && retry::retries_error(retry, &error) => {
+					log::warn!(
+						"{}: {}, retrying (attempt {} of {})",
+						context,
+						error,
+						attempt,
+						attempts
+					);
+				}
+				_ => {
+					log::error!("{} for {}: {}", error.status().as_u16(), context, error);
+					let mut res = upstream_error::error_response(&error, server).await;
+					res.extensions_mut().insert(timing);
+					return Ok(res);
+				}
+			},
+		}
+
+		tried.push(index);
+		if let Some(retry) = retry {
+			tokio::time::sleep(retry::backoff(retry, attempt)).await;
+		}
+	};
+	// read by the access log
+	res.extensions_mut().insert(timing);
+	let res = timeout::limit_body(res, timeouts, started, context);
+	// the upstream counts as busy until the response body is fully sent
+	let res = balancer::hold_until_body_end(res, active);
 
 	// check if response IGNORED_CACHED had in Content-Encoding
 	// if yes, return res
@@ -227,7 +539,7 @@ async fn proxy_request(
 	}
 
 	// if let Some(encoding) = get_prefered_encoding(&res.headers()) {
-	let mut res = compression::auto(&method, header, res).unwrap_or_else(|_| {
+	let mut res = compression::auto(method, header, res).unwrap_or_else(|_| {
 		Response::builder()
 			.status(StatusCode::INTERNAL_SERVER_ERROR)
 			.body("Internal Server Error".into())
@@ -241,11 +553,7 @@ async fn proxy_request(
 	match res.headers().get("cache-control") {
 		Some(cache_control) => {
 			if let Ok(cache_control) = cache_control.to_str() {
-				if cache_control.contains("no-cache")
-					|| cache_control.contains("no-store")
-					|| cache_control.contains("must-revalidate")
-					|| cache_control.contains("max-age=0")
-				{
+				if !control_headers::is_cacheable(cache_control) {
 					return Ok(res);
 				}
 			}
@@ -273,35 +581,24 @@ pub async fn handle(_req: Request<Body>) -> Result<Response<Body>, hyper::Error>
 	Ok(response)
 }
 
-fn get_ips_from_x_forwarded_for(req: &Request<hyper::Body>) -> Vec<IpAddr> {
-	let mut ips = Vec::new();
-	if let Some(header_value) = req.headers().get("X-Forwarded-For") {
-		if let Ok(forwarded_for) = header_value.to_str() {
-			for ip_str in forwarded_for.split(',').map(str::trim) {
-				if let Ok(ip) = ip_str.parse::<IpAddr>() {
-					ips.push(ip);
-				}
-			}
-		}
-	}
-	ips
-}
-
-fn get_ips_from_string(ip_str: &str) -> Vec<IpAddr> {
-	let mut ips = Vec::new();
-	for ip_str in ip_str.split(',').map(str::trim) {
-		if let Ok(ip) = ip_str.parse::<IpAddr>() {
-			ips.push(ip);
-		}
+// Value hashed by a `consistent_hash` route to pick its upstream
+fn balance_key(
+	route: &RouteMatch<'_>,
+	req: &Request<Body>,
+	client_ip: IpAddr,
+	visitor_id: Option<&str>,
+) -> Option<String> {
+	let balancer = &route.route.balancer;
+	if balancer.strategy() != LoadBalancing::ConsistentHash {
+		return None;
 	}
-	ips
-}
-
-fn is_ip_in_whitelist(ips: Vec<IpAddr>, whitelist: &HashSet<IpAddr>) -> bool {
-	for ip in ips {
-		if whitelist.contains(&ip) {
-			return true;
-		}
+	match balancer.hash_key() {
+		HashKey::ClientIp => Some(client_ip.to_string()),
+		HashKey::Header(name) => req
+			.headers()
+			.get(name.as_str())
+			.and_then(|value| value.to_str().ok())
+			.map(str::to_string),
+		HashKey::FpVisitor => visitor_id.map(str::to_string),
 	}
-	false
 }
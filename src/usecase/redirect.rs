@@ -0,0 +1,85 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use hyper::{header::LOCATION, Body, Request, Response, StatusCode};
+
+use super::router;
+use crate::{config::Server, state::AppState, utils::host};
+
+/// Answers requests on a TLS `redirect_listen` address with a permanent redirect to the HTTPS
+/// listener of the matching server block.
+pub async fn to_https(
+	req: Request<Body>,
+	state: Arc<AppState>,
+	listener: SocketAddr,
+) -> Result<Response<Body>, hyper::Error> {
+	let snapshot = state.snapshot();
+	let indices = snapshot
+		.redirects
+		.get(&listener)
+		.map(Vec::as_slice)
+		.unwrap_or_default();
+	let servers: Vec<&Server> = indices
+		.iter()
+		.map(|&index| &snapshot.config.http.servers[index])
+		.collect();
+
+	let host = host::request_host(&req);
+	let position =
+		router::select_server(&servers, host.as_deref(), snapshot.config.http.unknown_host);
+	let (Some(host), Some(position)) = (host, position) else {
+		return Ok(Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body("not found".into())
+			.unwrap());
+	};
+
+	// first address the server block terminates TLS on
+	let port = snapshot
+		.listeners
+		.iter()
+		.find(|(_, servers)| servers.contains(&indices[position]))
+		.map(|(address, _)| address.port())
+		.unwrap_or(443);
+	let location = https_location(&host, port, req.uri().path_and_query().map(|p| p.as_str()));
+
+	Ok(Response::builder()
+		.status(StatusCode::MOVED_PERMANENTLY)
+		.header(LOCATION, location)
+		.body(Body::empty())
+		.unwrap())
+}
+
+fn https_location(host: &str, port: u16, path_and_query: Option<&str>) -> String {
+	let host = if host.contains(':') {
+		format!("[{}]", host)
+	} else {
+		host.to_string()
+	};
+	let path = path_and_query.unwrap_or("/");
+	if port == 443 {
+		format!("https://{}{}", host, path)
+	} else {
+		format!("https://{}:{}{}", host, port, path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn builds_https_locations() {
+		assert_eq!(
+			https_location("example.com", 443, Some("/a?b=1")),
+			"https://example.com/a?b=1"
+		);
+		assert_eq!(
+			https_location("example.com", 8443, None),
+			"https://example.com:8443/"
+		);
+		assert_eq!(
+			https_location("::1", 8443, Some("/")),
+			"https://[::1]:8443/"
+		);
+	}
+}
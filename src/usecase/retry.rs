@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use hyper::{header::CONTENT_LENGTH, Body, HeaderMap, Method, StatusCode};
+
+use super::upstream_error::UpstreamError;
+use crate::config::{Retry, RetryOn};
+
+/// Request body of a proxied request, kept in memory when it may be sent again.
+pub enum RetryBody {
+	Buffered(Bytes),
+	Streaming(Body),
+}
+
+impl RetryBody {
+	/// Reads `body` into memory unless it is larger than `max_size`, in which case the part
+	/// already read is put back in front of the rest of the stream.
+	pub async fn buffer(
+		mut body: Body,
+		headers: &HeaderMap,
+		max_size: usize,
+	) -> Result<Self, hyper::Error> {
+		let content_length = headers
+			.get(CONTENT_LENGTH)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse::<usize>().ok());
+		if content_length.is_some_and(|length| length > max_size) {
+			return Ok(RetryBody::Streaming(body));
+		}
+
+		let mut buffered = BytesMut::new();
+		while let Some(chunk) = body.next().await {
+			buffered.extend_from_slice(&chunk?);
+			if buffered.len() > max_size {
+				let read = stream::once(async move { Ok::<_, hyper::Error>(buffered.freeze()) });
+				return Ok(RetryBody::Streaming(Body::wrap_stream(read.chain(body))));
+			}
+		}
+		Ok(RetryBody::Buffered(buffered.freeze()))
+	}
+
+	pub fn is_replayable(&self) -> bool {
+		matches!(self, RetryBody::Buffered(_))
+	}
+
+	/// Body for the next attempt. A streamed body can only be taken once.
+	pub fn take(&mut self) -> Body {
+		match self {
+			RetryBody::Buffered(bytes) => Body::from(bytes.clone()),
+			RetryBody::Streaming(body) => std::mem::take(body),
+		}
+	}
+}
+
+/// Whether the policy applies to requests with `method`.
+pub fn allows(retry: &Retry, method: &Method) -> bool {
+	retry.non_idempotent || method.is_idempotent()
+}
+
+/// Whether a failed attempt should be retried.
+pub fn retries_error(retry: &Retry, error: &UpstreamError) -> bool {
+	let kind = match error {
+		UpstreamError::ConnectTimeout(_) => RetryOn::ConnectError,
+		UpstreamError::Request(e) if e.is_connect() => RetryOn::ConnectError,
+		UpstreamError::Request(_) => RetryOn::Error,
+		UpstreamError::FirstByteTimeout(_) => RetryOn::Timeout,
+		// no time left for another attempt
+		UpstreamError::TotalTimeout(_) | UpstreamError::IdleBodyTimeout(_) => return false,
+	};
+	retry.on.contains(&kind)
+}
+
+pub fn retries_status(retry: &Retry, status: StatusCode) -> bool {
+	retry.statuses.contains(&status.as_u16())
+}
+
+/// Delay before retry number `retry_number` (1 for the first retry): exponential backoff capped
+/// at `max_backoff`, with full jitter so clients do not retry in lockstep.
+pub fn backoff(retry: &Retry, retry_number: u32) -> Duration {
+	let exponential = retry
+		.backoff
+		.saturating_mul(1u64 << retry_number.saturating_sub(1).min(32));
+	let cap = exponential.min(retry.max_backoff);
+	Duration::from_millis(fastrand::u64(0..=cap))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn retry() -> Retry {
+		serde_json::from_str(r#"{"statuses": [503], "backoff": 100, "max_backoff": 300}"#).unwrap()
+	}
+
+	#[test]
+	fn retries_idempotent_requests_by_default() {
+		let mut retry = retry();
+		assert!(allows(&retry, &Method::GET));
+		assert!(allows(&retry, &Method::PUT));
+		assert!(!allows(&retry, &Method::POST));
+		retry.non_idempotent = true;
+		assert!(allows(&retry, &Method::POST));
+
+		assert!(retries_status(&retry, StatusCode::SERVICE_UNAVAILABLE));
+		assert!(!retries_status(&retry, StatusCode::BAD_GATEWAY));
+		assert!(!retries_error(
+			&retry,
+			&UpstreamError::FirstByteTimeout(Duration::from_secs(1))
+		));
+		retry.on.push(RetryOn::Timeout);
+		assert!(retries_error(
+			&retry,
+			&UpstreamError::FirstByteTimeout(Duration::from_secs(1))
+		));
+	}
+
+	#[test]
+	fn backs_off_exponentially() {
+		let retry = retry();
+		for _ in 0..50 {
+			assert!(backoff(&retry, 1) <= Duration::from_millis(100));
+			assert!(backoff(&retry, 2) <= Duration::from_millis(200));
+			assert!(backoff(&retry, 10) <= Duration::from_millis(300));
+		}
+	}
+
+	#[tokio::test]
+	async fn buffers_bodies_up_to_the_limit() {
+		let mut body = RetryBody::buffer(Body::from("hello"), &HeaderMap::new(), 8)
+			.await
+			.unwrap();
+		assert!(body.is_replayable());
+		for _ in 0..2 {
+			let bytes = hyper::body::to_bytes(body.take()).await.unwrap();
+			assert_eq!(bytes, "hello");
+		}
+
+		let chunks: Vec<Result<_, hyper::Error>> = vec![Ok("hello "), Ok("world")];
+		let large = Body::wrap_stream(stream::iter(chunks));
+		let mut body = RetryBody::buffer(large, &HeaderMap::new(), 8)
+			.await
+			.unwrap();
+		assert!(!body.is_replayable());
+		let bytes = hyper::body::to_bytes(body.take()).await.unwrap();
+		assert_eq!(bytes, "hello world");
+	}
+}
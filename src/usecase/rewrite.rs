@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::{
+	config::{QueryRewrite, Rewrite},
+	prelude::*,
+	usecase::router::expand_captures,
+};
+
+/// A compiled `rewrite` rule of a proxy.
+#[derive(Debug)]
+pub struct PathRewrite {
+	regex: Regex,
+	replacement: String,
+}
+
+impl PathRewrite {
+	pub fn compile(rule: &Rewrite) -> Result<Self> {
+		let regex = Regex::new(&rule.pattern)
+			.map_err(|e| Error::Generic(f!("invalid rewrite pattern {:?}: {}", rule.pattern, e)))?;
+		Ok(PathRewrite {
+			regex,
+			replacement: rule.replacement.clone(),
+		})
+	}
+}
+
+/// Applies the first rule whose pattern matches `path`, replacing the match with the rule's
+/// replacement where `$1` or `$name` refer to the pattern's groups.
+///
+/// The path is returned unchanged when no rule matches.
+pub fn rewrite_path(path: &str, rules: &[PathRewrite]) -> String {
+	for rule in rules {
+		if rule.regex.is_match(path) {
+			return rule
+				.regex
+				.replace(path, rule.replacement.as_str())
+				.into_owned();
+		}
+	}
+	path.to_string()
+}
+
+/// Rewrites a path with [`rewrite_path`] and returns the new path and query string.
+///
+/// A replacement may carry its own query string (`/v2/users?id=$1`), which is put before the
+/// original one.
+pub fn rewrite_target(path: &str, query: &str, rules: &[PathRewrite]) -> (String, String) {
+	let rewritten = rewrite_path(path, rules);
+	match rewritten.split_once('?') {
+		Some((path, added)) if query.is_empty() => (path.to_string(), added.to_string()),
+		Some((path, added)) => (path.to_string(), format!("{}&{}", added, query)),
+		None => (rewritten, query.to_string()),
+	}
+}
+
+/// Removes, renames then adds query parameters.
+///
+/// Values of added parameters can reference the named groups of a regex route as `$name`.
+pub fn rewrite_query(
+	query: &str,
+	rules: &QueryRewrite,
+	captures: &HashMap<String, String>,
+) -> String {
+	if rules.add.is_empty() && rules.remove.is_empty() && rules.rename.is_empty() {
+		return query.to_string();
+	}
+
+	let mut params: Vec<(String, String)> = query
+		.split('&')
+		.filter(|pair| !pair.is_empty())
+		.map(|pair| match pair.split_once('=') {
+			Some((key, value)) => (key.to_string(), value.to_string()),
+			None => (pair.to_string(), String::new()),
+		})
+		.collect();
+
+	params.retain(|(key, _)| !rules.remove.contains(key));
+	for (key, _) in params.iter_mut() {
+		if let Some(renamed) = rules.rename.get(key.as_str()) {
+			*key = renamed.clone();
+		}
+	}
+	for (key, value) in &rules.add {
+		let key = encode_component(key);
+		let value = encode_component(&expand_captures(value, captures));
+		params.retain(|(existing, _)| *existing != key);
+		params.push((key, value));
+	}
+
+	params
+		.iter()
+		.map(|(key, value)| {
+			if value.is_empty() {
+				key.clone()
+			} else {
+				format!("{}={}", key, value)
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("&")
+}
+
+// Percent-encodes everything but unreserved characters (RFC 3986)
+fn encode_component(value: &str) -> String {
+	let mut encoded = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+				encoded.push(byte as char)
+			}
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+	encoded
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use super::*;
+
+	fn rule(pattern: &str, replacement: &str) -> PathRewrite {
+		PathRewrite::compile(&Rewrite {
+			pattern: pattern.into(),
+			replacement: replacement.into(),
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn rewrites_paths() {
+		let rules = [
+			rule(r"^/legacy/users/(\d+)$", "/v2/accounts/$1"),
+			rule(r"^/legacy/(?P<rest>.*)$", "/v2/${rest}"),
+		];
+		assert_eq!(rewrite_path("/legacy/users/42", &rules), "/v2/accounts/42");
+		assert_eq!(rewrite_path("/legacy/orders/7", &rules), "/v2/orders/7");
+		assert_eq!(rewrite_path("/other", &rules), "/other");
+	}
+
+	#[test]
+	fn rewrites_targets_with_queries() {
+		let rules = [rule(r"^/users/(\d+)$", "/v2/users?id=$1")];
+		assert_eq!(
+			rewrite_target("/users/5", "page=2", &rules),
+			("/v2/users".to_string(), "id=5&page=2".to_string())
+		);
+		assert_eq!(
+			rewrite_target("/users/5", "", &rules),
+			("/v2/users".to_string(), "id=5".to_string())
+		);
+		assert_eq!(
+			rewrite_target("/orders", "page=2", &rules),
+			("/orders".to_string(), "page=2".to_string())
+		);
+	}
+
+	#[test]
+	fn rewrites_queries() {
+		let rules = QueryRewrite {
+			add: BTreeMap::from([
+				("tenant".to_string(), "$tenant".to_string()),
+				("source".to_string(), "proxy gateway".to_string()),
+			]),
+			remove: vec!["debug".to_string()],
+			rename: BTreeMap::from([("q".to_string(), "query".to_string())]),
+		};
+		let captures = HashMap::from([("tenant".to_string(), "acme".to_string())]);
+
+		assert_eq!(
+			rewrite_query("q=rust&debug=1&page=2&source=x", &rules, &captures),
+			"query=rust&page=2&source=proxy%20gateway&tenant=acme"
+		);
+		assert_eq!(rewrite_query("", &QueryRewrite::default(), &captures), "");
+		assert_eq!(
+			rewrite_query("flag&a=1", &QueryRewrite::default(), &captures),
+			"flag&a=1"
+		);
+	}
+}
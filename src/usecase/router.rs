@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::{
+	config::{MatchType, Proxy, Server, Timeouts, UnknownHost},
+	prelude::*,
+	usecase::{
+		access::AccessRule,
+		balancer::{Balancer, UpstreamStates},
+		rewrite::PathRewrite,
+		timeout::UpstreamTimeouts,
+	},
+	utils::host::match_server_name,
+};
+
+/// Picks the server block that should handle a request for `host` and returns its position in `servers`.
+///
+/// The most specific name wins (exact names before wildcards, longer wildcards before shorter ones).
+/// Without a match the block flagged `default_server` is used, then `unknown_host` decides
+/// whether the first block serves the request or `None` is returned.
+pub fn select_server(
+	servers: &[&Server],
+	host: Option<&str>,
+	unknown_host: UnknownHost,
+) -> Option<usize> {
+	if let Some(host) = host {
+		let mut best: Option<(usize, usize)> = None;
+		for (position, server) in servers.iter().enumerate() {
+			for name in server.host_names() {
+				if let Some(rank) = match_server_name(name, host) {
+					if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+						best = Some((rank, position));
+					}
+				}
+			}
+		}
+		if let Some((_, position)) = best {
+			return Some(position);
+		}
+	}
+
+	if let Some(position) = servers.iter().position(|server| server.default_server) {
+		return Some(position);
+	}
+
+	match unknown_host {
+		UnknownHost::FirstServer if !servers.is_empty() => Some(0),
+		_ => None,
+	}
+}
+
+/// A proxy entry with its path pattern, rewrite rules and upstreams compiled.
+#[derive(Debug)]
+pub struct Route {
+	regex: Option<Regex>,
+	rewrites: Vec<PathRewrite>,
+	pub balancer: Balancer,
+	pub timeouts: UpstreamTimeouts,
+	pub access: Option<AccessRule>,
+}
+
+impl Route {
+	/// Compiles a proxy, `defaults` being the global timeouts.
+	pub fn compile(
+		proxy: &Proxy,
+		defaults: &Timeouts,
+		upstreams: &mut UpstreamStates,
+	) -> Result<Self> {
+		let regex = match proxy.match_type {
+			MatchType::Regex => Some(Regex::new(&proxy.proxy_path).map_err(|e| {
+				Error::Generic(f!("invalid proxy_path regex {:?}: {}", proxy.proxy_path, e))
+			})?),
+			MatchType::Prefix | MatchType::Exact => None,
+		};
+		let rewrites = proxy
+			.rewrite
+			.iter()
+			.map(PathRewrite::compile)
+			.collect::<Result<Vec<_>>>()?;
+		Ok(Route {
+			regex,
+			rewrites,
+			balancer: Balancer::new(proxy, upstreams),
+			timeouts: UpstreamTimeouts::resolve(&proxy.timeouts, defaults),
+			access: proxy.access.as_ref().map(AccessRule::compile).transpose()?,
+		})
+	}
+
+	pub fn rewrites(&self) -> &[PathRewrite] {
+		&self.rewrites
+	}
+
+	/// Compiles every proxy of a server block, in order.
+	pub fn compile_all(
+		server: &Server,
+		defaults: &Timeouts,
+		upstreams: &mut UpstreamStates,
+	) -> Result<Vec<Self>> {
+		server
+			.proxies
+			.iter()
+			.map(|proxy| Route::compile(proxy, defaults, upstreams))
+			.collect::<Result<Vec<_>>>()
+			.map_err(|e| Error::Generic(f!("server {:?}: {}", server.name, e)))
+	}
+}
+
+/// The proxy chosen for a request path.
+#[derive(Debug)]
+pub struct RouteMatch<'a> {
+	pub proxy: &'a Proxy,
+	/// Request path without the matched part, forwarded when `retain_path` is set
+	pub rest: String,
+	pub route: &'a Route,
+	/// Named capture groups of a regex route
+	pub captures: HashMap<String, String>,
+}
+
+/// Picks the proxy for a request path. `routes` must be compiled from `proxies`, in the same order.
+///
+/// Priority:
+/// 1. `exact` routes equal to the path
+/// 2. `regex` routes, the first one declared that matches
+/// 3. `prefix` routes matching whole path segments, the longest one wins
+pub fn select_route<'a>(
+	proxies: &'a [Proxy],
+	routes: &'a [Route],
+	path: &str,
+) -> Option<RouteMatch<'a>> {
+	let routes = proxies.iter().zip(routes);
+
+	if let Some((proxy, route)) = routes
+		.clone()
+		.find(|(proxy, _)| proxy.match_type == MatchType::Exact && proxy.proxy_path == path)
+	{
+		return Some(RouteMatch {
+			proxy,
+			route,
+			rest: String::new(),
+			captures: HashMap::new(),
+		});
+	}
+
+	for (proxy, route) in routes.clone() {
+		let Some(regex) = &route.regex else {
+			continue;
+		};
+		if let Some(captures) = regex.captures(path) {
+			let matched = captures.get(0).map_or(0..0, |m| m.range());
+			let named = regex
+				.capture_names()
+				.flatten()
+				.filter_map(|name| {
+					Some((name.to_string(), captures.name(name)?.as_str().to_string()))
+				})
+				.collect();
+			// the path without the match, still starting with `/` so that it cannot run into the
+			// authority of the upstream URL it is appended to
+			let rest = format!("{}{}", &path[..matched.start], &path[matched.end..]);
+			let rest = if rest.starts_with('/') {
+				rest
+			} else {
+				format!("/{}", rest)
+			};
+			return Some(RouteMatch {
+				proxy,
+				rest,
+				route,
+				captures: named,
+			});
+		}
+	}
+
+	routes
+		.filter(|(proxy, _)| proxy.match_type == MatchType::Prefix)
+		.filter(|(proxy, _)| matches_segments(&proxy.proxy_path, path))
+		.max_by_key(|(proxy, _)| proxy.proxy_path.trim_end_matches('/').len())
+		.map(|(proxy, route)| RouteMatch {
+			proxy,
+			// without the trailing slash of `proxy_path`, `rest` is empty or starts with `/`
+			rest: path
+				.strip_prefix(proxy.proxy_path.trim_end_matches('/'))
+				.unwrap_or_default()
+				.to_string(),
+			route,
+			captures: HashMap::new(),
+		})
+}
+
+/// Replaces `$name` and `${name}` in `template` with the named captures of a route.
+///
+/// Unknown names are left untouched.
+pub fn expand_captures(template: &str, captures: &HashMap<String, String>) -> String {
+	if captures.is_empty() || !template.contains('$') {
+		return template.to_string();
+	}
+
+	let mut expanded = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('$') {
+		expanded.push_str(&rest[..start]);
+		let after = &rest[start + 1..];
+		let (name, consumed) = match after.strip_prefix('{') {
+			Some(braced) => match braced.find('}') {
+				Some(end) => (&braced[..end], end + 2),
+				None => ("", 0),
+			},
+			None => {
+				let end = after
+					.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+					.unwrap_or(after.len());
+				(&after[..end], end)
+			}
+		};
+		match captures.get(name) {
+			Some(value) if !name.is_empty() => expanded.push_str(value),
+			_ => expanded.push_str(&rest[start..start + 1 + consumed]),
+		}
+		rest = &after[consumed..];
+	}
+	expanded.push_str(rest);
+	expanded
+}
+
+/// Checks that `prefix` matches `path` on whole segments, so `/api` matches `/api` and `/api/users`
+/// but not `/apiary`.
+fn matches_segments(prefix: &str, path: &str) -> bool {
+	let prefix = prefix.trim_end_matches('/');
+	match path.strip_prefix(prefix) {
+		Some(rest) => rest.is_empty() || rest.starts_with('/'),
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hyper::Uri;
+
+	use super::*;
+
+	fn server(name: &str, server_names: &[&str], default_server: bool) -> Server {
+		Server {
+			name: name.into(),
+			server_names: server_names.iter().map(|s| s.to_string()).collect(),
+			default_server,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn selects_by_host() {
+		let owned = [
+			server("wild", &["*.example.com"], false),
+			server("api", &["api.example.com"], false),
+			server("other.test", &[], false),
+		];
+		let servers: Vec<&Server> = owned.iter().collect();
+
+		let pick = |host| {
+			select_server(&servers, host, UnknownHost::NotFound).map(|i| servers[i].name.as_str())
+		};
+		assert_eq!(pick(Some("api.example.com")), Some("api"));
+		assert_eq!(pick(Some("www.example.com")), Some("wild"));
+		assert_eq!(pick(Some("other.test")), Some("other.test"));
+		assert_eq!(pick(Some("unknown.test")), None);
+		assert_eq!(pick(None), None);
+	}
+
+	#[test]
+	fn falls_back_for_unknown_hosts() {
+		let owned = [server("a.test", &[], false), server("b.test", &[], true)];
+		let servers: Vec<&Server> = owned.iter().collect();
+		let picked = select_server(&servers, Some("c.test"), UnknownHost::NotFound);
+		assert_eq!(picked, Some(1));
+
+		let owned = [server("a.test", &[], false), server("b.test", &[], false)];
+		let servers: Vec<&Server> = owned.iter().collect();
+		let picked = select_server(&servers, Some("c.test"), UnknownHost::FirstServer);
+		assert_eq!(picked, Some(0));
+		assert!(select_server(&servers, Some("c.test"), UnknownHost::Misdirected).is_none());
+	}
+
+	fn proxy(path: &str, match_type: MatchType) -> Proxy {
+		Proxy {
+			proxy_pass: format!("http://upstream{}", path),
+			proxy_path: path.into(),
+			match_type,
+			..Default::default()
+		}
+	}
+
+	fn compile(proxies: &[Proxy]) -> Vec<Route> {
+		proxies
+			.iter()
+			.map(|p| Route::compile(p, &Timeouts::default(), &mut HashMap::new()).unwrap())
+			.collect()
+	}
+
+	#[test]
+	fn matches_prefixes_on_segments() {
+		let proxies = [
+			proxy("/", MatchType::Prefix),
+			proxy("/api", MatchType::Prefix),
+			proxy("/api/v2/", MatchType::Prefix),
+		];
+		let routes = compile(&proxies);
+		let path_of =
+			|path| select_route(&proxies, &routes, path).map(|m| m.proxy.proxy_path.clone());
+
+		assert_eq!(path_of("/api").as_deref(), Some("/api"));
+		assert_eq!(path_of("/api/users").as_deref(), Some("/api"));
+		assert_eq!(path_of("/apiary").as_deref(), Some("/"));
+		assert_eq!(path_of("/api/v2").as_deref(), Some("/api/v2/"));
+		assert_eq!(path_of("/api/v2/users").as_deref(), Some("/api/v2/"));
+		assert_eq!(path_of("/api/v20").as_deref(), Some("/api"));
+
+		let matched = select_route(&proxies, &routes, "/api/users/api").unwrap();
+		assert_eq!(matched.rest, "/users/api");
+		let matched = select_route(&proxies, &routes, "/api/v2/users").unwrap();
+		assert_eq!(matched.rest, "/users");
+		let matched = select_route(&proxies, &routes, "/api/v2").unwrap();
+		assert_eq!(matched.rest, "");
+	}
+
+	#[test]
+	fn exact_and_regex_routes_take_priority() {
+		let proxies = [
+			proxy("/users", MatchType::Prefix),
+			proxy(r"^/users/(?P<id>\d+)/avatar", MatchType::Regex),
+			proxy("/users/me", MatchType::Exact),
+		];
+		let routes = compile(&proxies);
+
+		let matched = select_route(&proxies, &routes, "/users/me").unwrap();
+		assert_eq!(matched.proxy.match_type, MatchType::Exact);
+
+		let matched = select_route(&proxies, &routes, "/users/42/avatar.png").unwrap();
+		assert_eq!(matched.proxy.match_type, MatchType::Regex);
+		assert_eq!(matched.captures.get("id").map(String::as_str), Some("42"));
+		assert_eq!(matched.rest, "/.png");
+
+		let matched = select_route(&proxies, &routes, "/users/me/settings").unwrap();
+		assert_eq!(matched.proxy.match_type, MatchType::Prefix);
+		assert!(select_route(&proxies, &routes, "/other").is_none());
+	}
+
+	#[test]
+	fn rest_stays_on_the_upstream() {
+		let proxies = [
+			proxy(r"^/u/(?P<id>\d+)", MatchType::Regex),
+			proxy("/api/", MatchType::Prefix),
+		];
+		let routes = compile(&proxies);
+
+		for path in [
+			"/u/1@evil.example/x",
+			"/u/1.evil.example",
+			"/u/1",
+			"/api/@evil.example",
+		] {
+			let matched = select_route(&proxies, &routes, path).unwrap();
+			let uri: Uri = format!("http://upstream:8080{}", matched.rest)
+				.parse()
+				.unwrap();
+			assert_eq!(uri.host(), Some("upstream"), "{}", path);
+			assert_eq!(uri.port_u16(), Some(8080), "{}", path);
+		}
+	}
+
+	#[test]
+	fn expands_named_captures() {
+		let captures = HashMap::from([("id".to_string(), "42".to_string())]);
+		assert_eq!(expand_captures("user-$id", &captures), "user-42");
+		assert_eq!(expand_captures("${id}th", &captures), "42th");
+		assert_eq!(expand_captures("$other $id $", &captures), "$other 42 $");
+		assert_eq!(expand_captures("${id", &captures), "${id");
+	}
+
+	#[test]
+	fn rejects_invalid_regex() {
+		let proxy = proxy("^/(unclosed", MatchType::Regex);
+		assert!(Route::compile(&proxy, &Timeouts::default(), &mut HashMap::new()).is_err());
+	}
+}
@@ -5,7 +5,10 @@ use lol_html::{html_content::ContentType, HtmlRewriter, Settings};
 use mime_guess::from_path;
 use tokio::fs::read;
 
-use crate::utils::{compression, control_headers, security_headers};
+use crate::{
+	trace::{self, Span, SpanKind},
+	utils::{compression, control_headers, security_headers},
+};
 
 // List of web file extensions
 const WEB_EXTENSIONS: [&str; 11] = [
@@ -102,7 +105,10 @@ fn serve_html_with_scripts(
 	scripts: Vec<&str>,
 	onloadfunction: Option<&String>,
 ) -> Response<Body> {
-	let html = match fs::read_to_string(file_path) {
+	let span = trace::span("read file", SpanKind::Internal);
+	let html = fs::read_to_string(file_path);
+	trace_read(span, file_path, &html);
+	let html = match html {
 		Ok(html) => html,
 		Err(e) => {
 			println!("Failed to read file: {}", e);
@@ -145,7 +151,7 @@ fn serve_html_with_scripts(
 	rewriter.write(html.as_bytes()).unwrap();
 	rewriter.end().unwrap();
 
-	let mime_type = from_path(&file_path).first_or_octet_stream();
+	let mime_type = from_path(file_path).first_or_octet_stream();
 	let mime_str = mime_type.as_ref();
 	let mut response = Response::new(Body::from(output_buffer));
 	response.headers_mut().insert(
@@ -161,9 +167,12 @@ async fn serve_default_static(
 	method: &Method,
 	headers: &HeaderMap<HeaderValue>,
 ) -> Response<Body> {
-	match read(file_check).await {
+	let span = trace::span("read file", SpanKind::Internal);
+	let file = read(file_check).await;
+	trace_read(span, file_check, &file);
+	match file {
 		Ok(bytes) => {
-			let mime_type = from_path(&file_check).first_or_octet_stream();
+			let mime_type = from_path(file_check).first_or_octet_stream();
 			let mime_str = mime_type.as_ref();
 
 			// check if the MIME type is compressible
@@ -195,3 +204,15 @@ async fn serve_default_static(
 		}
 	}
 }
+
+// Records a file read in the trace of the request
+fn trace_read<T: AsRef<[u8]>>(span: Option<Span>, path: &str, read: &std::io::Result<T>) {
+	let Some(mut span) = span else {
+		return;
+	};
+	span.set("file.path", path);
+	match read {
+		Ok(content) => span.set("file.size", content.as_ref().len() as i64),
+		Err(e) => span.set_error(e.to_string()),
+	}
+}
@@ -0,0 +1,227 @@
+use std::{
+	pin::Pin,
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use hyper::{Body, Response};
+use tokio::time::{Instant, Sleep};
+
+use super::upstream_error::UpstreamError;
+use crate::config::Timeouts;
+
+const DEFAULT_CONNECT: u64 = 10;
+const DEFAULT_FIRST_BYTE: u64 = 60;
+const DEFAULT_IDLE_BODY: u64 = 60;
+
+/// Upstream timeouts of a proxy, with the global and built-in defaults applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UpstreamTimeouts {
+	pub connect: Option<Duration>,
+	pub first_byte: Option<Duration>,
+	pub total: Option<Duration>,
+	pub idle_body: Option<Duration>,
+}
+
+impl UpstreamTimeouts {
+	pub fn resolve(proxy: &Timeouts, defaults: &Timeouts) -> Self {
+		let timeouts = proxy.or(defaults);
+		UpstreamTimeouts {
+			connect: seconds(timeouts.connect.unwrap_or(DEFAULT_CONNECT)),
+			first_byte: seconds(timeouts.first_byte.unwrap_or(DEFAULT_FIRST_BYTE)),
+			total: timeouts.total.and_then(seconds),
+			idle_body: seconds(timeouts.idle_body.unwrap_or(DEFAULT_IDLE_BODY)),
+		}
+	}
+}
+
+fn seconds(value: u64) -> Option<Duration> {
+	(value > 0).then(|| Duration::from_secs(value))
+}
+
+/// Waits for the response headers, failing after the first byte timeout or when the total
+/// timeout started at `started` runs out first.
+pub async fn response_within<F>(
+	response: F,
+	timeouts: &UpstreamTimeouts,
+	started: Instant,
+) -> Result<Response<Body>, UpstreamError>
+where
+	F: Future<Output = Result<Response<Body>, hyper::Error>>,
+{
+	let total_left = timeouts
+		.total
+		.map(|total| total.saturating_sub(started.elapsed()));
+	let limit = match (timeouts.first_byte, total_left) {
+		(Some(first_byte), Some(left)) if left < first_byte => Some((
+			left,
+			UpstreamError::TotalTimeout(timeouts.total.unwrap_or_default()),
+		)),
+		(Some(first_byte), _) => Some((first_byte, UpstreamError::FirstByteTimeout(first_byte))),
+		(None, Some(left)) => Some((
+			left,
+			UpstreamError::TotalTimeout(timeouts.total.unwrap_or_default()),
+		)),
+		(None, None) => None,
+	};
+
+	match limit {
+		Some((limit, error)) => match tokio::time::timeout(limit, response).await {
+			Ok(result) => result.map_err(UpstreamError::request),
+			Err(_) => Err(error),
+		},
+		None => response.await.map_err(UpstreamError::request),
+	}
+}
+
+/// Applies the idle and total timeouts to a response body. Once the headers are sent the
+/// client can only see the body being cut short, so each timeout is logged with `context`.
+pub fn limit_body(
+	res: Response<Body>,
+	timeouts: &UpstreamTimeouts,
+	started: Instant,
+	context: String,
+) -> Response<Body> {
+	if timeouts.idle_body.is_none() && timeouts.total.is_none() {
+		return res;
+	}
+	let (parts, body) = res.into_parts();
+	let body = TimeoutBody {
+		body,
+		idle: timeouts
+			.idle_body
+			.map(|idle| (idle, Box::pin(tokio::time::sleep(idle)))),
+		deadline: timeouts
+			.total
+			.map(|total| (total, Box::pin(tokio::time::sleep_until(started + total)))),
+		context,
+	};
+	Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+struct TimeoutBody {
+	body: Body,
+	idle: Option<(Duration, Pin<Box<Sleep>>)>,
+	deadline: Option<(Duration, Pin<Box<Sleep>>)>,
+	context: String,
+}
+
+impl Stream for TimeoutBody {
+	type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = &mut *self;
+		match Pin::new(&mut this.body).poll_next(cx) {
+			Poll::Ready(Some(chunk)) => {
+				if let Some((idle, timer)) = &mut this.idle {
+					timer.as_mut().reset(Instant::now() + *idle);
+				}
+				return Poll::Ready(Some(chunk.map_err(Into::into)));
+			}
+			Poll::Ready(None) => return Poll::Ready(None),
+			Poll::Pending => {}
+		}
+
+		let error = if let Some((total, timer)) = &mut this.deadline {
+			timer
+				.as_mut()
+				.poll(cx)
+				.map(|_| UpstreamError::TotalTimeout(*total))
+		} else {
+			Poll::Pending
+		};
+		let error = match (error, &mut this.idle) {
+			(Poll::Pending, Some((idle, timer))) => timer
+				.as_mut()
+				.poll(cx)
+				.map(|_| UpstreamError::IdleBodyTimeout(*idle)),
+			(error, _) => error,
+		};
+		match error {
+			Poll::Ready(error) => {
+				log::error!("{}: {}, response cut short", this.context, error);
+				// stop polling the timers and the upstream body
+				this.idle = None;
+				this.deadline = None;
+				this.body = Body::empty();
+				Poll::Ready(Some(Err(error.to_string().into())))
+			}
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::StreamExt;
+
+	use super::*;
+
+	#[test]
+	fn resolves_timeouts() {
+		let defaults = Timeouts {
+			first_byte: Some(30),
+			total: Some(120),
+			..Default::default()
+		};
+		let proxy = Timeouts {
+			first_byte: Some(5),
+			idle_body: Some(0),
+			..Default::default()
+		};
+		assert_eq!(
+			UpstreamTimeouts::resolve(&proxy, &defaults),
+			UpstreamTimeouts {
+				connect: Some(Duration::from_secs(10)),
+				first_byte: Some(Duration::from_secs(5)),
+				total: Some(Duration::from_secs(120)),
+				idle_body: None,
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn times_out_waiting_for_headers() {
+		let timeouts = UpstreamTimeouts {
+			first_byte: Some(Duration::from_millis(20)),
+			..Default::default()
+		};
+		let hung = futures::future::pending();
+		let error = response_within(hung, &timeouts, Instant::now())
+			.await
+			.unwrap_err();
+		assert!(matches!(error, UpstreamError::FirstByteTimeout(_)));
+
+		let timeouts = UpstreamTimeouts {
+			first_byte: Some(Duration::from_secs(10)),
+			total: Some(Duration::from_millis(20)),
+			..Default::default()
+		};
+		let hung = futures::future::pending();
+		let error = response_within(hung, &timeouts, Instant::now())
+			.await
+			.unwrap_err();
+		assert!(matches!(error, UpstreamError::TotalTimeout(_)));
+	}
+
+	#[tokio::test]
+	async fn cuts_idle_bodies() {
+		let (mut sender, body) = Body::channel();
+		let timeouts = UpstreamTimeouts {
+			idle_body: Some(Duration::from_millis(20)),
+			..Default::default()
+		};
+		let res = limit_body(
+			Response::new(body),
+			&timeouts,
+			Instant::now(),
+			"test".into(),
+		);
+		sender.send_data("first".into()).await.unwrap();
+		let mut body = res.into_body();
+		assert_eq!(body.next().await.unwrap().unwrap(), "first");
+		assert!(body.next().await.unwrap().is_err());
+	}
+}
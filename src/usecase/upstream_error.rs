@@ -0,0 +1,182 @@
+use std::{error::Error as StdError, fmt, io, path::Path, time::Duration};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use serde_json::json;
+
+use crate::config::{ErrorPage, Server};
+
+/// Why a proxied request got no usable response from its upstream.
+#[derive(Debug)]
+pub enum UpstreamError {
+	/// Connection refused or reset, DNS failure, invalid response...
+	Request(hyper::Error),
+	/// The connection was not established within the connect timeout
+	ConnectTimeout(hyper::Error),
+	/// No response headers within the first byte timeout
+	FirstByteTimeout(Duration),
+	/// The exchange did not complete within the total timeout
+	TotalTimeout(Duration),
+	/// The response body stalled longer than the idle body timeout
+	IdleBodyTimeout(Duration),
+}
+
+impl UpstreamError {
+	pub fn request(e: hyper::Error) -> Self {
+		if e.is_connect() && is_timeout(&e) {
+			UpstreamError::ConnectTimeout(e)
+		} else {
+			UpstreamError::Request(e)
+		}
+	}
+
+	/// `504 Gateway Timeout` for timeouts, `502 Bad Gateway` otherwise.
+	pub fn status(&self) -> StatusCode {
+		match self {
+			UpstreamError::Request(e) if !is_timeout(e) => StatusCode::BAD_GATEWAY,
+			_ => StatusCode::GATEWAY_TIMEOUT,
+		}
+	}
+
+	// Shown to clients, unlike the logged message
+	fn detail(&self) -> &'static str {
+		match self {
+			UpstreamError::Request(e) if is_timeout(e) => {
+				"The upstream server did not respond in time."
+			}
+			UpstreamError::Request(_) => "The upstream server could not be reached.",
+			UpstreamError::ConnectTimeout(_) => "The connection to the upstream server timed out.",
+			UpstreamError::FirstByteTimeout(_) => {
+				"The upstream server did not start responding in time."
+			}
+			UpstreamError::TotalTimeout(_) => {
+				"The upstream server did not complete the response in time."
+			}
+			UpstreamError::IdleBodyTimeout(_) => "The upstream server stopped sending data.",
+		}
+	}
+}
+
+impl fmt::Display for UpstreamError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			UpstreamError::Request(e) => write!(f, "{}", e),
+			UpstreamError::ConnectTimeout(e) => write!(f, "connect timeout: {}", e),
+			UpstreamError::FirstByteTimeout(timeout) => {
+				write!(f, "first byte timeout, no response after {:?}", timeout)
+			}
+			UpstreamError::TotalTimeout(timeout) => {
+				write!(f, "total timeout, not complete after {:?}", timeout)
+			}
+			UpstreamError::IdleBodyTimeout(timeout) => {
+				write!(f, "idle body timeout, no data for {:?}", timeout)
+			}
+		}
+	}
+}
+
+// hyper only flags its own timeouts, connect timeouts come as a wrapped io error
+fn is_timeout(e: &hyper::Error) -> bool {
+	if e.is_timeout() {
+		return true;
+	}
+	let mut source = e.source();
+	while let Some(err) = source {
+		if let Some(io) = err.downcast_ref::<io::Error>() {
+			if io.kind() == io::ErrorKind::TimedOut {
+				return true;
+			}
+		}
+		source = err.source();
+	}
+	false
+}
+
+/// Builds the response for a failed upstream with the server's `error_page`.
+///
+/// An HTML page that cannot be read falls back to the plain text body.
+pub async fn error_response(error: &UpstreamError, server: &Server) -> Response<Body> {
+	let status = error.status();
+	let title = status.canonical_reason().unwrap_or("Upstream Error");
+	let plain = format!("{}: {}", title, error.detail());
+	let (content_type, body) = match &server.error_page {
+		ErrorPage::Plain => ("text/plain; charset=utf-8", plain),
+		ErrorPage::ProblemJson => (
+			"application/problem+json",
+			json!({
+				"type": "about:blank",
+				"title": title,
+				"status": status.as_u16(),
+				"detail": error.detail(),
+			})
+			.to_string(),
+		),
+		ErrorPage::Html { file } => {
+			let path = Path::new(&server.root).join(file.replace("{status}", status.as_str()));
+			match tokio::fs::read_to_string(&path).await {
+				Ok(html) => ("text/html; charset=utf-8", html),
+				Err(e) => {
+					log::error!("failed to read error page {}: {}", path.display(), e);
+					("text/plain; charset=utf-8", plain)
+				}
+			}
+		}
+	};
+
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, content_type)
+		.body(body.into())
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn body(res: Response<Body>) -> String {
+		let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		String::from_utf8(bytes.to_vec()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn renders_error_pages() {
+		let mut server = Server::default();
+		let timeout = UpstreamError::FirstByteTimeout(Duration::from_secs(1));
+		let idle = UpstreamError::IdleBodyTimeout(Duration::from_secs(1));
+
+		let res = error_response(&timeout, &server).await;
+		assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+		assert_eq!(
+			body(res).await,
+			"Gateway Timeout: The upstream server did not start responding in time."
+		);
+
+		server.error_page = ErrorPage::ProblemJson;
+		let res = error_response(&idle, &server).await;
+		assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+		let problem: serde_json::Value = serde_json::from_str(&body(res).await).unwrap();
+		assert_eq!(problem["status"], 504);
+		assert_eq!(problem["title"], "Gateway Timeout");
+		assert_eq!(
+			problem["detail"],
+			"The upstream server stopped sending data."
+		);
+
+		let root = std::env::temp_dir().join("rust-reverse-proxy-error-page");
+		std::fs::create_dir_all(&root).unwrap();
+		std::fs::write(root.join("504.html"), "<h1>slow</h1>").unwrap();
+		server.root = root.to_string_lossy().into_owned();
+		server.error_page = ErrorPage::Html {
+			file: "{status}.html".into(),
+		};
+		let res = error_response(&timeout, &server).await;
+		assert_eq!(body(res).await, "<h1>slow</h1>");
+		// missing pages fall back to plain text
+		std::fs::remove_file(root.join("504.html")).unwrap();
+		let res = error_response(&idle, &server).await;
+		assert_eq!(
+			body(res).await,
+			"Gateway Timeout: The upstream server stopped sending data."
+		);
+	}
+}
@@ -0,0 +1,136 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::HeaderMap;
+
+use crate::{config::ClientIpHeader, utils::ip_list::IpTrie};
+
+/// Address of the client behind a request.
+///
+/// Only a peer in `trusted` is believed about the client address in `header`. Its hops are read
+/// from right to left, skipping the trusted ones: the first untrusted hop is the client. When the
+/// header is missing or malformed, the last address known for sure is used.
+pub fn resolve(
+	headers: &HeaderMap,
+	peer: SocketAddr,
+	trusted: &IpTrie,
+	header: ClientIpHeader,
+) -> IpAddr {
+	let mut client = peer.ip().to_canonical();
+	if !trusted.contains(client) {
+		return client;
+	}
+	let hops = match header {
+		ClientIpHeader::XForwardedFor => header_values(headers, "x-forwarded-for")
+			.flat_map(|value| value.split(','))
+			.map(|hop| hop.trim().parse::<IpAddr>().ok())
+			.collect(),
+		ClientIpHeader::Forwarded => header_values(headers, "forwarded")
+			.flat_map(|value| value.split(','))
+			.map(forwarded_for)
+			.collect(),
+		ClientIpHeader::XRealIp => header_values(headers, "x-real-ip")
+			.last()
+			.map(|value| vec![value.trim().parse::<IpAddr>().ok()])
+			.unwrap_or_default(),
+	};
+	for hop in hops.into_iter().rev() {
+		let Some(hop) = hop else {
+			// an unknown or obfuscated hop hides everything before it
+			break;
+		};
+		client = hop.to_canonical();
+		if !trusted.contains(client) {
+			break;
+		}
+	}
+	client
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+	headers
+		.get_all(name)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+}
+
+// The `for` parameter of one RFC 7239 element, e.g. `for=192.0.2.60;proto=http` or
+// `for="[2001:db8:cafe::17]:4711"`
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+	let value = element.split(';').find_map(|pair| {
+		let (name, value) = pair.split_once('=')?;
+		name.trim()
+			.eq_ignore_ascii_case("for")
+			.then(|| value.trim().trim_matches('"'))
+	})?;
+	if let Some(rest) = value.strip_prefix('[') {
+		return rest.split_once(']')?.0.parse().ok();
+	}
+	value
+		.parse()
+		.ok()
+		.or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn trusted() -> IpTrie {
+		let mut trusted = IpTrie::default();
+		trusted.insert("10.0.0.0/8".parse().unwrap());
+		trusted
+	}
+
+	fn resolve_with(header: ClientIpHeader, name: &str, value: &str, peer: &str) -> String {
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+			value.parse().unwrap(),
+		);
+		resolve(&headers, peer.parse().unwrap(), &trusted(), header).to_string()
+	}
+
+	#[test]
+	fn walks_x_forwarded_for_from_the_right() {
+		let xff = |value, peer| {
+			resolve_with(
+				ClientIpHeader::XForwardedFor,
+				"x-forwarded-for",
+				value,
+				peer,
+			)
+		};
+		// untrusted peers cannot speak for anyone else
+		assert_eq!(xff("1.1.1.1", "203.0.113.5:1234"), "203.0.113.5");
+		assert_eq!(xff("1.1.1.1, 2.2.2.2", "10.0.0.1:1234"), "2.2.2.2");
+		assert_eq!(
+			xff("1.1.1.1, 2.2.2.2, 10.0.0.9", "10.0.0.1:1234"),
+			"2.2.2.2"
+		);
+		assert_eq!(xff("10.0.0.8, 10.0.0.9", "10.0.0.1:1234"), "10.0.0.8");
+		assert_eq!(
+			xff("1.1.1.1, garbage, 10.0.0.9", "10.0.0.1:1234"),
+			"10.0.0.9"
+		);
+		assert_eq!(xff("1.1.1.1", "[::ffff:10.0.0.1]:1234"), "1.1.1.1");
+	}
+
+	#[test]
+	fn reads_forwarded_and_x_real_ip() {
+		let forwarded =
+			|value| resolve_with(ClientIpHeader::Forwarded, "forwarded", value, "10.0.0.1:1");
+		assert_eq!(
+			forwarded(r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.9"#),
+			"2001:db8:cafe::17"
+		);
+		assert_eq!(
+			forwarded("For=\"192.0.2.60:443\";by=10.0.0.1"),
+			"192.0.2.60"
+		);
+		assert_eq!(forwarded("for=192.0.2.60, for=_hidden"), "10.0.0.1");
+
+		let real_ip = |value, peer| resolve_with(ClientIpHeader::XRealIp, "x-real-ip", value, peer);
+		assert_eq!(real_ip("192.0.2.60", "10.0.0.1:1"), "192.0.2.60");
+		assert_eq!(real_ip("192.0.2.60", "192.0.2.1:1"), "192.0.2.1");
+	}
+}
@@ -2,8 +2,11 @@ use async_compression::tokio::bufread::BrotliEncoder;
 use async_compression::tokio::bufread::DeflateEncoder;
 use async_compression::tokio::bufread::GzipEncoder;
 
+use crate::metrics::METRICS;
+use crate::trace::{self, SpanKind};
 use crate::utils::exts::http::MethodExt;
 use bytes::Bytes;
+use futures::StreamExt;
 use futures_util::Stream;
 use headers::{AcceptEncoding, ContentCoding, ContentType, HeaderMap, HeaderMapExt};
 use hyper::http;
@@ -94,7 +97,12 @@ pub fn gzip(
 	mut head: http::response::Parts,
 	body: CompressableBody<Body, hyper::Error>,
 ) -> Response<Body> {
-	let body = Body::wrap_stream(ReaderStream::new(GzipEncoder::new(StreamReader::new(body))));
+	let encoder = GzipEncoder::new(StreamReader::new(counted(body, ContentCoding::GZIP, false)));
+	let body = Body::wrap_stream(counted(
+		ReaderStream::new(encoder),
+		ContentCoding::GZIP,
+		true,
+	));
 	let header = create_encoding_header(head.headers.remove(CONTENT_ENCODING), ContentCoding::GZIP);
 	head.headers.remove(CONTENT_LENGTH);
 	head.headers.append(CONTENT_ENCODING, header);
@@ -107,9 +115,16 @@ pub fn deflate(
 	mut head: http::response::Parts,
 	body: CompressableBody<Body, hyper::Error>,
 ) -> Response<Body> {
-	let body = Body::wrap_stream(ReaderStream::new(DeflateEncoder::new(StreamReader::new(
+	let encoder = DeflateEncoder::new(StreamReader::new(counted(
 		body,
-	))));
+		ContentCoding::DEFLATE,
+		false,
+	)));
+	let body = Body::wrap_stream(counted(
+		ReaderStream::new(encoder),
+		ContentCoding::DEFLATE,
+		true,
+	));
 	let header = create_encoding_header(
 		head.headers.remove(CONTENT_ENCODING),
 		ContentCoding::DEFLATE,
@@ -125,9 +140,16 @@ pub fn brotli(
 	mut head: http::response::Parts,
 	body: CompressableBody<Body, hyper::Error>,
 ) -> Response<Body> {
-	let body = Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(StreamReader::new(
+	let encoder = BrotliEncoder::new(StreamReader::new(counted(
 		body,
-	))));
+		ContentCoding::BROTLI,
+		false,
+	)));
+	let body = Body::wrap_stream(counted(
+		ReaderStream::new(encoder),
+		ContentCoding::BROTLI,
+		true,
+	));
 	let header =
 		create_encoding_header(head.headers.remove(CONTENT_ENCODING), ContentCoding::BROTLI);
 	head.headers.remove(CONTENT_LENGTH);
@@ -135,6 +157,34 @@ pub fn brotli(
 	Response::from_parts(head, body)
 }
 
+// Counts the bytes going into (`output` false) or out of an encoder, for the compression ratio
+// in the metrics. The output also gets a span in the trace of the request, ending with the body.
+fn counted<S>(
+	body: S,
+	coding: ContentCoding,
+	output: bool,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+	S: Stream<Item = std::io::Result<Bytes>>,
+{
+	let mut span = output
+		.then(|| trace::span("compress", SpanKind::Internal))
+		.flatten();
+	if let Some(span) = &mut span {
+		span.set("http.response.content_encoding", coding.to_static());
+	}
+	let mut total = 0;
+	body.inspect(move |chunk| {
+		if let Ok(chunk) = chunk {
+			METRICS.record_compression(coding.to_static(), output, chunk.len());
+			if let Some(span) = &mut span {
+				total += chunk.len() as i64;
+				span.set("http.response.body.size", total);
+			}
+		}
+	})
+}
+
 /// Given an optional existing encoding header, appends to the existing or creates a new one.
 pub fn create_encoding_header(existing: Option<HeaderValue>, coding: ContentCoding) -> HeaderValue {
 	if let Some(val) = existing {
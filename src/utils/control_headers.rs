@@ -1,11 +1,14 @@
 use headers::{CacheControl, HeaderMapExt};
-use hyper::{Body, Response};
+use hyper::{header::CACHE_CONTROL, Body, HeaderMap, Response, StatusCode};
 
 // Cache-Control `max-age` variants
 const MAX_AGE_ONE_HOUR: u64 = 60 * 60;
 const MAX_AGE_ONE_DAY: u64 = 60 * 60 * 24;
 const MAX_AGE_ONE_YEAR: u64 = 60 * 60 * 24 * 365;
 
+// `Cache-Control` directives keeping a response out of caches, or from being used without a check
+const NOT_CACHEABLE: [&str; 4] = ["no-cache", "no-store", "must-revalidate", "max-age=0"];
+
 // `Cache-Control` list of extensions
 const CACHE_EXT_ONE_HOUR: [&str; 4] = ["atom", "json", "rss", "xml"];
 const CACHE_EXT_ONE_YEAR: [&str; 32] = [
@@ -34,6 +37,34 @@ pub fn append_headers(uri: &str, resp: &mut Response<Body>) {
 	resp.headers_mut().typed_insert(cache_control);
 }
 
+/// If a `Cache-Control` value lets caches reuse the response.
+pub fn is_cacheable(cache_control: &str) -> bool {
+	!NOT_CACHEABLE
+		.iter()
+		.any(|directive| cache_control.contains(directive))
+}
+
+/// Outcome of a response for the client's cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+	/// `304 Not Modified`, the client reuses its copy
+	Hit,
+	/// A successful response the client may cache
+	Miss,
+}
+
+/// The cache outcome of a response, `None` when it cannot be cached.
+pub fn cache_status(status: StatusCode, headers: &HeaderMap) -> Option<CacheStatus> {
+	if status == StatusCode::NOT_MODIFIED {
+		return Some(CacheStatus::Hit);
+	}
+	let cacheable = headers
+		.get(CACHE_CONTROL)
+		.and_then(|value| value.to_str().ok())
+		.is_some_and(is_cacheable);
+	(status.is_success() && cacheable).then_some(CacheStatus::Miss)
+}
+
 /// It caps a duration value at ~136 years.
 fn duration_from_secs(secs: u64) -> std::time::Duration {
 	std::time::Duration::from_secs(std::cmp::min(secs, u32::MAX as u64))
@@ -48,11 +79,11 @@ fn uri_file_extension(uri: &str) -> Option<&str> {
 
 #[cfg(test)]
 mod tests {
-	use hyper::{http, Body, Response, StatusCode};
+	use hyper::{http, Body, HeaderMap, Response, StatusCode};
 
 	use super::{
-		append_headers, uri_file_extension, CACHE_EXT_ONE_HOUR, CACHE_EXT_ONE_YEAR,
-		MAX_AGE_ONE_DAY, MAX_AGE_ONE_HOUR, MAX_AGE_ONE_YEAR,
+		append_headers, cache_status, uri_file_extension, CacheStatus, CACHE_EXT_ONE_HOUR,
+		CACHE_EXT_ONE_YEAR, MAX_AGE_ONE_DAY, MAX_AGE_ONE_HOUR, MAX_AGE_ONE_YEAR,
 	};
 
 	#[tokio::test]
@@ -104,6 +135,29 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn cache_outcomes() {
+		let mut resp = Response::new(Body::empty());
+		append_headers("/app.js", &mut resp);
+		assert_eq!(
+			cache_status(StatusCode::OK, resp.headers()),
+			Some(CacheStatus::Miss)
+		);
+		assert_eq!(
+			cache_status(StatusCode::NOT_MODIFIED, &HeaderMap::new()),
+			Some(CacheStatus::Hit)
+		);
+		assert_eq!(cache_status(StatusCode::NOT_FOUND, resp.headers()), None);
+		assert_eq!(cache_status(StatusCode::OK, &HeaderMap::new()), None);
+
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			http::header::CACHE_CONTROL,
+			"private, no-store".parse().unwrap(),
+		);
+		assert_eq!(cache_status(StatusCode::OK, &headers), None);
+	}
+
 	#[test]
 	fn find_uri_extension() {
 		assert_eq!(uri_file_extension("/potato.zip"), Some("zip"));
@@ -0,0 +1,119 @@
+use hyper::{header::HOST, Request};
+
+/// Extracts the host a request was sent to, without port, lowercased.
+///
+/// The URI authority (`:authority` on HTTP/2 or an absolute-form request target) wins over the `Host` header.
+pub fn request_host<B>(req: &Request<B>) -> Option<String> {
+	let authority = match req.uri().authority() {
+		Some(authority) => authority.as_str().to_string(),
+		None => req.headers().get(HOST)?.to_str().ok()?.to_string(),
+	};
+	normalize_host(&authority)
+}
+
+/// Strips userinfo, port and trailing dot from an authority and lowercases it.
+pub fn normalize_host(authority: &str) -> Option<String> {
+	let authority = authority.rsplit('@').next().unwrap_or(authority).trim();
+	let host = if let Some(rest) = authority.strip_prefix('[') {
+		// IPv6 literal, e.g. `[::1]:8080`
+		&rest[..rest.find(']')?]
+	} else {
+		match authority.rsplit_once(':') {
+			Some((host, _port)) => host,
+			None => authority,
+		}
+	};
+	let host = host.trim_end_matches('.');
+	if host.is_empty() {
+		return None;
+	}
+	Some(host.to_ascii_lowercase())
+}
+
+/// Checks a host against a server name pattern and returns how specific the match is.
+///
+/// Supported patterns:
+/// - `example.com` matches only `example.com`
+/// - `*.example.com` matches any subdomain of `example.com`, but not `example.com` itself
+/// - `.example.com` matches `example.com` and any of its subdomains
+/// - `*` matches any host
+///
+/// Exact matches rank above every wildcard and longer wildcards rank above shorter ones.
+pub fn match_server_name(pattern: &str, host: &str) -> Option<usize> {
+	let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+
+	if pattern == "*" {
+		return Some(0);
+	}
+
+	if let Some(suffix) = pattern.strip_prefix("*.") {
+		let matched = host.len() > suffix.len() + 1
+			&& host.ends_with(suffix)
+			&& host.as_bytes()[host.len() - suffix.len() - 1] == b'.';
+		return matched.then_some(suffix.len());
+	}
+
+	if let Some(suffix) = pattern.strip_prefix('.') {
+		let matched = host == suffix
+			|| (host.len() > suffix.len() + 1
+				&& host.ends_with(suffix)
+				&& host.as_bytes()[host.len() - suffix.len() - 1] == b'.');
+		return matched.then_some(suffix.len());
+	}
+
+	(pattern == host).then_some(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn normalizes_authorities() {
+		assert_eq!(normalize_host("Example.COM"), Some("example.com".into()));
+		assert_eq!(
+			normalize_host("example.com:8080"),
+			Some("example.com".into())
+		);
+		assert_eq!(normalize_host("example.com."), Some("example.com".into()));
+		assert_eq!(
+			normalize_host("user@example.com:80"),
+			Some("example.com".into())
+		);
+		assert_eq!(normalize_host("[::1]:3400"), Some("::1".into()));
+		assert_eq!(normalize_host("[::1]"), Some("::1".into()));
+		assert_eq!(normalize_host(":80"), None);
+	}
+
+	#[test]
+	fn matches_server_names() {
+		assert_eq!(
+			match_server_name("example.com", "example.com"),
+			Some(usize::MAX)
+		);
+		assert_eq!(
+			match_server_name("Example.com", "example.com"),
+			Some(usize::MAX)
+		);
+		assert_eq!(match_server_name("example.com", "www.example.com"), None);
+
+		assert_eq!(
+			match_server_name("*.example.com", "www.example.com"),
+			Some(11)
+		);
+		assert_eq!(
+			match_server_name("*.example.com", "a.b.example.com"),
+			Some(11)
+		);
+		assert_eq!(match_server_name("*.example.com", "example.com"), None);
+		assert_eq!(match_server_name("*.example.com", "badexample.com"), None);
+
+		assert_eq!(match_server_name(".example.com", "example.com"), Some(11));
+		assert_eq!(
+			match_server_name(".example.com", "www.example.com"),
+			Some(11)
+		);
+
+		assert_eq!(match_server_name("*", "anything.test"), Some(0));
+	}
+}
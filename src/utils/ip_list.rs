@@ -0,0 +1,265 @@
+use std::{
+	fmt,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	str::FromStr,
+};
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/48`. A bare address is a
+/// range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+	// network address, host bits cleared
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+		let addr = addr.to_canonical();
+		let bits = bit_len(&addr);
+		if prefix > bits {
+			return None;
+		}
+		let mask = if prefix == 0 {
+			0
+		} else {
+			u128::MAX << (bits - prefix) & (u128::MAX >> (128 - bits))
+		};
+		let addr = match addr {
+			IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((to_bits(&addr) & mask) as u32)),
+			IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(to_bits(&addr) & mask)),
+		};
+		Some(Cidr { addr, prefix })
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || format!("invalid IP or CIDR range {:?}", s);
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+			None => bit_len(&addr.to_canonical()),
+		};
+		Cidr::new(addr, prefix).ok_or_else(invalid)
+	}
+}
+
+impl fmt::Display for Cidr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.prefix == bit_len(&self.addr) {
+			write!(f, "{}", self.addr)
+		} else {
+			write!(f, "{}/{}", self.addr, self.prefix)
+		}
+	}
+}
+
+fn bit_len(addr: &IpAddr) -> u8 {
+	match addr {
+		IpAddr::V4(_) => 32,
+		IpAddr::V6(_) => 128,
+	}
+}
+
+// Address bits, left aligned on the address length
+fn to_bits(addr: &IpAddr) -> u128 {
+	match addr {
+		IpAddr::V4(v4) => u32::from(*v4) as u128,
+		IpAddr::V6(v6) => u128::from(*v6),
+	}
+}
+
+/// Binary prefix trie of CIDR ranges, one per address family. Looking up an address walks at
+/// most one node per bit, however many ranges are stored.
+#[derive(Debug, Clone, Default)]
+pub struct IpTrie {
+	v4: Node,
+	v6: Node,
+	len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+	// a range ends here
+	end: bool,
+	children: [Option<Box<Node>>; 2],
+}
+
+impl IpTrie {
+	/// Adds a range, returns false when it was already there.
+	pub fn insert(&mut self, cidr: Cidr) -> bool {
+		let bits = bit_len(&cidr.addr);
+		let value = to_bits(&cidr.addr);
+		let mut node = match cidr.addr {
+			IpAddr::V4(_) => &mut self.v4,
+			IpAddr::V6(_) => &mut self.v6,
+		};
+		for i in 0..cidr.prefix {
+			let bit = (value >> (bits - 1 - i) & 1) as usize;
+			node = node.children[bit].get_or_insert_with(Default::default);
+		}
+		let added = !node.end;
+		node.end = true;
+		self.len += added as usize;
+		added
+	}
+
+	/// Whether a range holds `ip`. IPv4-mapped IPv6 addresses match IPv4 ranges.
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		let ip = ip.to_canonical();
+		let bits = bit_len(&ip);
+		let value = to_bits(&ip);
+		let mut node = match ip {
+			IpAddr::V4(_) => &self.v4,
+			IpAddr::V6(_) => &self.v6,
+		};
+		for i in 0..bits {
+			if node.end {
+				return true;
+			}
+			let bit = (value >> (bits - 1 - i) & 1) as usize;
+			match &node.children[bit] {
+				Some(child) => node = child,
+				None => return false,
+			}
+		}
+		node.end
+	}
+
+	/// Adds every range of `other`.
+	pub fn merge(&mut self, other: &IpTrie) {
+		fn merge_node(into: &mut Node, from: &Node) -> usize {
+			let mut added = (from.end && !into.end) as usize;
+			into.end |= from.end;
+			for (bit, child) in from.children.iter().enumerate() {
+				if let Some(child) = child {
+					let into = into.children[bit].get_or_insert_with(Default::default);
+					added += merge_node(into, child);
+				}
+			}
+			added
+		}
+		self.len += merge_node(&mut self.v4, &other.v4) + merge_node(&mut self.v6, &other.v6);
+	}
+
+	/// Number of ranges.
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+}
+
+/// Allowed and denied ranges. Denied ranges win over allowed ones.
+#[derive(Debug, Clone, Default)]
+pub struct IpList {
+	pub allow: IpTrie,
+	pub deny: IpTrie,
+}
+
+impl IpList {
+	/// Parses entries separated by commas or new lines. An entry starting with `!` is denied,
+	/// empty entries and lines starting with `#` are skipped. Returns the list and the entries
+	/// that are not valid addresses or ranges.
+	pub fn parse(text: &str) -> (Self, Vec<String>) {
+		let mut list = IpList::default();
+		let mut invalid = Vec::new();
+		for entry in text.split([',', '\n']).map(str::trim) {
+			if entry.is_empty() || entry.starts_with('#') {
+				continue;
+			}
+			if list.add(entry).is_err() {
+				invalid.push(entry.to_string());
+			}
+		}
+		(list, invalid)
+	}
+
+	pub fn merge(&mut self, other: &IpList) {
+		self.allow.merge(&other.allow);
+		self.deny.merge(&other.deny);
+	}
+
+	/// Adds one entry, `!` marking a denied one.
+	pub fn add(&mut self, entry: &str) -> Result<(), String> {
+		match entry.strip_prefix('!') {
+			Some(denied) => self.deny.insert(denied.trim().parse()?),
+			None => self.allow.insert(entry.parse()?),
+		};
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn parses_cidr_ranges() {
+		assert_eq!(
+			"10.1.2.3/8".parse::<Cidr>().unwrap().to_string(),
+			"10.0.0.0/8"
+		);
+		assert_eq!(
+			"2001:db8:1:2::/48".parse::<Cidr>().unwrap().to_string(),
+			"2001:db8:1::/48"
+		);
+		assert_eq!(
+			"::ffff:1.2.3.4".parse::<Cidr>().unwrap().to_string(),
+			"1.2.3.4"
+		);
+		assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+		assert!("10.0.0/8".parse::<Cidr>().is_err());
+	}
+
+	#[test]
+	fn matches_addresses_in_ranges() {
+		let mut trie = IpTrie::default();
+		assert!(trie.insert("10.0.0.0/8".parse().unwrap()));
+		assert!(trie.insert("192.168.1.7".parse().unwrap()));
+		assert!(trie.insert("2001:db8:1::/48".parse().unwrap()));
+		assert!(!trie.insert("10.9.9.9/8".parse().unwrap()));
+		assert_eq!(trie.len(), 3);
+
+		assert!(trie.contains(ip("10.200.3.4")));
+		assert!(trie.contains(ip("::ffff:10.0.0.1")));
+		assert!(trie.contains(ip("192.168.1.7")));
+		assert!(!trie.contains(ip("192.168.1.8")));
+		assert!(!trie.contains(ip("11.0.0.1")));
+		assert!(trie.contains(ip("2001:db8:1:ffff::1")));
+		assert!(!trie.contains(ip("2001:db8:2::1")));
+
+		let mut everything = IpTrie::default();
+		everything.insert("0.0.0.0/0".parse().unwrap());
+		assert!(everything.contains(ip("8.8.8.8")));
+		assert!(!everything.contains(ip("::1")));
+	}
+
+	#[test]
+	fn parses_allowed_and_denied_entries() {
+		let (mut list, invalid) =
+			IpList::parse("10.0.0.0/8, !10.6.0.0/16\n# partners\n2001:db8::/32\nnope");
+		assert_eq!(invalid, ["nope"]);
+		assert_eq!((list.allow.len(), list.deny.len()), (2, 1));
+		assert!(list.allow.contains(ip("10.6.0.1")));
+		assert!(list.deny.contains(ip("10.6.0.1")));
+
+		list.merge(&IpList::parse("10.0.0.0/8, 192.0.2.0/24, !192.0.2.1").0);
+		assert_eq!((list.allow.len(), list.deny.len()), (3, 2));
+		assert!(list.allow.contains(ip("192.0.2.9")));
+		assert!(list.deny.contains(ip("192.0.2.1")));
+	}
+}
@@ -1,7 +1,14 @@
+// debugging helpers nothing uses at the moment
+#[allow(dead_code)]
 pub mod body_clone;
+pub mod client_ip;
 pub mod compression;
 pub mod control_headers;
 pub mod cookie;
+#[allow(dead_code)]
 pub mod exts;
 pub mod fingerprintjs;
+pub mod host;
+pub mod ip_list;
+pub mod redact;
 pub mod security_headers;